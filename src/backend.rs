@@ -0,0 +1,236 @@
+use std::io::{stdout, Write};
+
+use crossterm::{event, terminal};
+use crossterm::event::Event;
+
+// Where the keys, mouse events and resizes come from
+pub trait Input {
+    // Waits for the next event, an error of kind UnexpectedEof means there won't be any more
+    fn read(&mut self) -> std::io::Result<Event>;
+}
+
+// Where the frames go
+pub trait Output {
+    // Columns and rows of the screen
+    fn size(&self) -> std::io::Result<(u16, u16)>;
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()>;
+
+    // What the written bytes left on the screen, for the tests to check,
+    // only a headless screen keeps track of it
+    #[cfg(test)]
+    fn lines(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[cfg(test)]
+    fn cursor(&self) -> (u16, u16) {
+        (0, 0)
+    }
+}
+
+// The terminal the editor runs in
+pub struct Terminal;
+
+impl Input for Terminal {
+    fn read(&mut self) -> std::io::Result<Event> {
+        event::read()
+    }
+}
+
+impl Output for Terminal {
+    fn size(&self) -> std::io::Result<(u16, u16)> {
+        terminal::size()
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut stdout = stdout();
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    }
+}
+
+// Events given up front, for running the editor without a terminal
+#[cfg(test)]
+pub struct Script {
+    events: std::collections::VecDeque<Event>
+}
+
+#[cfg(test)]
+impl Script {
+    pub fn new(events: Vec<Event>) -> Self {
+        Self { events: events.into() }
+    }
+}
+
+#[cfg(test)]
+impl Input for Script {
+    fn read(&mut self) -> std::io::Result<Event> {
+        self.events
+            .pop_front()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "end of the script"))
+    }
+}
+
+// A screen of a fixed size which nobody looks at, the bytes written to it are
+// played like a terminal would, into cells which the tests read back
+#[cfg(test)]
+pub struct Headless {
+    width: u16,
+    height: u16,
+    // The grapheme in each cell, the right half of a wide one is left empty
+    cells: Vec<String>,
+    cursor: (u16, u16),
+    // An escape sequence or a character which the last write cut off
+    pending: Vec<u8>
+}
+
+#[cfg(test)]
+impl Headless {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![String::from(" "); width as usize * height as usize],
+            cursor: (0, 0),
+            pending: Vec::new()
+        }
+    }
+
+    // Function to act on one escape sequence, only moving the cursor and
+    // clearing the screen matter for what is shown
+    fn escape(&mut self, params: &str, command: u8) {
+        match command {
+            b'H' => {
+                let mut numbers = params.split(';').map(|n| n.parse::<u16>().unwrap_or(1).max(1));
+                let row = numbers.next().unwrap_or(1);
+                let column = numbers.next().unwrap_or(1);
+                self.cursor = (column - 1, row - 1);
+            },
+            b'J' if params == "2" => self.cells.fill(String::from(" ")),
+            // Colours, attributes, showing the cursor and mouse reporting
+            _ => {}
+        }
+    }
+
+    // Function to write text at the cursor the way a terminal does, a wide grapheme
+    // takes two cells and anything past the end of the line is lost
+    fn print(&mut self, text: &str) {
+        use unicode_segmentation::UnicodeSegmentation;
+        use unicode_width::UnicodeWidthStr;
+
+        for g in text.graphemes(true) {
+            let (x, y) = (self.cursor.0 as usize, self.cursor.1 as usize);
+            let width = self.width as usize;
+            let w = g.width();
+            if y >= self.height as usize {
+                return;
+            }
+            let idx = y * width + x;
+            if w == 0 {
+                if x > 0 {
+                    self.cells[idx - 1].push_str(g);
+                }
+                continue;
+            }
+            if x + w > width {
+                return;
+            }
+            // Writing over half of a wide grapheme blanks the other half
+            if self.cells[idx].is_empty() && x > 0 {
+                self.cells[idx - 1] = String::from(" ");
+            }
+            let end = idx + w - 1;
+            if self.cells[end].width() > 1 && x + w < width {
+                self.cells[end + 1] = String::from(" ");
+            }
+            self.cells[idx] = g.to_string();
+            for cell in &mut self.cells[idx + 1..=end] {
+                cell.clear();
+            }
+            self.cursor.0 += w as u16;
+        }
+    }
+
+    // The text on the screen line by line, without the blanks at the end of the lines
+    pub fn lines(&self) -> Vec<String> {
+        self.cells
+            .chunks(self.width.max(1) as usize)
+            .map(|line| line.concat().trim_end().to_string())
+            .collect()
+    }
+
+    // Where the terminal cursor is, as (column, row)
+    pub fn cursor(&self) -> (u16, u16) {
+        self.cursor
+    }
+}
+
+#[cfg(test)]
+impl Output for Headless {
+    fn size(&self) -> std::io::Result<(u16, u16)> {
+        Ok((self.width, self.height))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let mut data = std::mem::take(&mut self.pending);
+        data.extend_from_slice(bytes);
+
+        let mut at = 0;
+        while at < data.len() {
+            match data[at] {
+                0x1b => match data.get(at + 1) {
+                    // CSI: parameters, then a final byte from '@' to '~'
+                    Some(b'[') => match data[at + 2..].iter().position(|b| (0x40..=0x7e).contains(b)) {
+                        Some(len) => {
+                            let params = String::from_utf8_lossy(&data[at + 2..at + 2 + len]).into_owned();
+                            self.escape(params.trim_start_matches('?'), data[at + 2 + len]);
+                            at += len + 3;
+                        },
+                        None => break
+                    },
+                    // OSC, like the clipboard, up to a BEL
+                    Some(b']') => match data[at..].iter().position(|&b| b == 0x07) {
+                        Some(len) => at += len + 1,
+                        None => break
+                    },
+                    Some(_) => at += 2,
+                    None => break
+                },
+                b'\r' => {
+                    self.cursor.0 = 0;
+                    at += 1;
+                },
+                b'\n' => {
+                    self.cursor.1 += 1;
+                    at += 1;
+                },
+                _ => {
+                    let len = data[at..].iter().position(|&b| b == 0x1b || b == b'\r' || b == b'\n').unwrap_or(data.len() - at);
+                    // A character cut in two waits for the rest of it
+                    let text = match std::str::from_utf8(&data[at..at + len]) {
+                        Ok(text) => text,
+                        Err(err) if err.error_len().is_none() => {
+                            let valid = err.valid_up_to();
+                            self.print(std::str::from_utf8(&data[at..at + valid]).unwrap());
+                            at += valid;
+                            break;
+                        },
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not UTF-8"))
+                    };
+                    self.print(text);
+                    at += len;
+                }
+            }
+        }
+        self.pending = data[at..].to_vec();
+        Ok(())
+    }
+
+    fn lines(&self) -> Vec<String> {
+        Headless::lines(self)
+    }
+
+    fn cursor(&self) -> (u16, u16) {
+        Headless::cursor(self)
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::ErrorKind;
+
+use crate::row::*;
+use crate::history::*;
+use crate::fileio::*;
+use crate::syntax::*;
+
+use kilo_ed_rust::*;
+
+// An open file (or an unnamed one) with everything which belongs to it,
+// so that several of them can be edited side by side
+pub struct Buffer {
+    pub rows: Vec<Row>,
+    pub filename: String,
+    pub dirty: usize,
+    pub history: History,
+    pub line_ending: LineEnding,
+    // The file had other line endings as well, saving turns them all into line_ending
+    pub mixed_endings: bool,
+    pub final_newline: bool,
+    pub encoding: Encoding,
+    pub read_only: bool,
+    pub syntax: Option<&'static Syntax>,
+    // Where the view was when another buffer got shown instead
+    pub cursor: CursorPos,
+    pub rowoff: u16,
+    pub coloff: u16
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer::build(&[], "")
+    }
+
+    // Function to read a file into a new buffer
+    // Also returns a message for the status bar if something is off with the file
+    pub fn open(filename: &str) -> (Self, Option<String>) {
+        let bytes = match std::fs::read(filename) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                return (Buffer::build(&[], filename), Some(String::from("New file")));
+            },
+            // Opening it read-only so that saving can't overwrite what we couldn't read
+            Err(err) => {
+                let mut buffer = Buffer::build(&[], filename);
+                buffer.read_only = true;
+                return (buffer, Some(format!("Can't open file: {}", err)));
+            }
+        };
+
+        let decoded = decode(&bytes);
+        let (lines, line_ending, final_newline, mixed_endings) = split_lines(&decoded.text);
+
+        let mut buffer = Buffer::build(&lines, filename);
+        buffer.line_ending = line_ending;
+        buffer.mixed_endings = mixed_endings;
+        buffer.final_newline = final_newline;
+        buffer.encoding = decoded.encoding;
+        if decoded.binary {
+            buffer.read_only = true;
+            return (buffer, Some(String::from("Warning!! Binary file (has NUL bytes), opened read-only")));
+        }
+        if mixed_endings {
+            let msg = format!("Mixed line endings, saving makes them all {}", line_ending.name());
+            return (buffer, Some(msg));
+        }
+        (buffer, None)
+    }
+
+    fn build<T: Into<String>>(data: &[String], filename: T) -> Self {
+        let filename = filename.into();
+        let mut buffer = Self {
+            rows: data.iter().map(|row| Row::new(row.to_string())).collect(),
+            syntax: Syntax::for_file(&filename),
+            filename,
+            dirty: 0,
+            history: History::new(),
+            line_ending: LineEnding::Lf,
+            mixed_endings: false,
+            final_newline: true,
+            encoding: Encoding::Utf8,
+            read_only: false,
+            cursor: CursorPos::default(),
+            rowoff: 0,
+            coloff: 0
+        };
+        buffer.update_syntax(0, buffer.rows.len());
+        buffer
+    }
+
+    // Name shown in the status bar and the buffer list
+    pub fn name(&self) -> &str {
+        if self.filename.is_empty() { "[No Name]" } else { &self.filename }
+    }
+
+    pub fn set_filename(&mut self, filename: String) {
+        self.filename = filename;
+        self.syntax = Syntax::for_file(&self.filename);
+        self.update_syntax(0, self.rows.len());
+    }
+
+    // Function to make a change to the rows and record it for undo
+    pub fn do_edit(&mut self, edit: Edit, cursor: CursorPos) {
+        self.history.begin(cursor, false);
+        self.apply_edit(&edit);
+        self.history.record(edit);
+        self.history.commit(cursor);
+        self.dirty += 1;
+    }
+
+    // Function to make a change to the rows without recording it
+    pub fn apply_edit(&mut self, edit: &Edit) {
+        // Rows whose text changed and need highlighting again
+        let (from, to) = match edit {
+            Edit::Insert { at, text } => {
+                let mut lines = text.split('\n');
+                let first = lines.next().unwrap_or_default();
+                let rest = self.rows[at.row].rowsplit(at.byte);
+                self.rows[at.row].append_string(first);
+
+                let mut last = at.row;
+                for line in lines {
+                    last += 1;
+                    self.rows.insert(last, Row::new(line.to_string()));
+                }
+                self.rows[last].append_string(&rest);
+                (at.row, last)
+            },
+
+            Edit::Delete { at, text } => {
+                let end = at.after(text);
+                let rest = self.rows[end.row].characters[end.byte..].to_string();
+                self.rows.drain(at.row + 1..=end.row);
+                self.rows[at.row].rowsplit(at.byte);
+                self.rows[at.row].append_string(&rest);
+                (at.row, at.row)
+            },
+
+            Edit::InsertRow { at, text } => {
+                self.rows.insert(*at, Row::new(text.to_string()));
+                (*at, *at)
+            },
+
+            Edit::DeleteRow { at, .. } => {
+                self.rows.remove(*at);
+                (*at, *at)
+            }
+        };
+        self.update_syntax(from, to);
+    }
+
+    // Function to render every row again, after the tab stop changed
+    pub fn render_again(&mut self) {
+        for row in &mut self.rows {
+            row.render = Row::render_row(&row.characters);
+        }
+        self.update_syntax(0, self.rows.len());
+    }
+
+    // Function to highlight the rows [from, to] and then the ones after them
+    // for as long as a multi-line comment opened or closed above carries over
+    pub fn update_syntax(&mut self, from: usize, to: usize) {
+        let mut in_comment = from > 0 && self.rows[from - 1].hl_open_comment;
+
+        for idx in from..self.rows.len() {
+            let changed = self.rows[idx].update_highlight(self.syntax, in_comment);
+            in_comment = self.rows[idx].hl_open_comment;
+            if idx >= to && !changed {
+                break;
+            }
+        }
+    }
+
+    // Takes back the last step, returns where the cursor was before it
+    pub fn undo(&mut self) -> Option<CursorPos> {
+        let step = self.history.undo()?;
+        for edit in step.edits.iter().rev() {
+            self.apply_edit(&edit.inverse());
+        }
+        self.update_dirty();
+        Some(step.cursor_before)
+    }
+
+    pub fn redo(&mut self) -> Option<CursorPos> {
+        let step = self.history.redo()?;
+        for edit in &step.edits {
+            self.apply_edit(edit);
+        }
+        self.update_dirty();
+        Some(step.cursor_after)
+    }
+
+    // After undo/redo the file is clean again only if we are back at the saved state
+    fn update_dirty(&mut self) {
+        if self.history.is_clean() {
+            self.dirty = 0;
+        } else {
+            self.dirty += 1;
+        }
+    }
+
+    // Joins the rows back with the line ending of the file, the last row
+    // only gets one if the file had it
+    pub fn row_to_string(&self) -> String {
+        let mut data = String::new();
+        let ending = self.line_ending.as_str();
+
+        for (idx, row) in self.rows.iter().enumerate() {
+            data.push_str(&row.characters);
+            if idx + 1 < self.rows.len() || self.final_newline {
+                data.push_str(ending);
+            }
+        }
+
+        data
+    }
+
+    // Function to write the buffer to its file
+    // Returns the number of bytes written or why it failed
+    pub fn save(&mut self) -> std::result::Result<usize, String> {
+        let len = self.write_to(&self.filename)?;
+
+        self.dirty = 0;
+        self.mixed_endings = false;
+        self.history.mark_saved();
+        Ok(len)
+    }
+
+    // Function to write the buffer to another file, it stays unsaved and keeps its name
+    pub fn write_to(&self, filename: &str) -> std::result::Result<usize, String> {
+        let buf = self.encoding.encode(&self.row_to_string())?;
+        write_atomic(filename, &buf)
+            .map_err(|err| format!("I/O error: {}", err))?;
+        Ok(buf.len())
+    }
+}
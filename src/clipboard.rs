@@ -0,0 +1,111 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::config::*;
+use crate::screen::*;
+
+// Text cut, copied or yanked, to be pasted or put back
+#[derive(Clone)]
+pub struct Register {
+    pub text: String,
+    // Whole lines are pasted as lines of their own instead of inside one
+    pub linewise: bool
+}
+
+impl Register {
+    // The text as the rest of the desktop sees it, whole lines end with a newline
+    fn system_text(&self) -> String {
+        if self.linewise { format!("{}\n", self.text) } else { self.text.clone() }
+    }
+}
+
+// What was last cut or copied, also handed to the terminal and to
+// the copy helper of the config so that it can be pasted elsewhere
+#[derive(Default)]
+pub struct Clipboard {
+    register: Option<Register>
+}
+
+impl Clipboard {
+    // Function to keep the register and copy it to the system clipboard
+    // Returns why the copy helper failed, the register is kept anyway
+    pub fn store(&mut self, register: Register, config: &Config, screen: &mut Screen) -> Result<(), String> {
+        let text = register.system_text();
+        self.register = Some(register);
+
+        if config.osc52 {
+            // A terminal which doesn't know OSC 52 ignores it
+            let _ = screen.set_clipboard(&base64(text.as_bytes()));
+        }
+        match helper(&config.copy_command) {
+            Some(mut command) => copy_with(&mut command, &text)
+                .map_err(|err| format!("Copy command '{}' failed: {}", config.copy_command, err)),
+            None => Ok(())
+        }
+    }
+
+    // Function to get what to paste, the system clipboard wins if the paste
+    // helper of the config has something else in it than what we copied last
+    pub fn load(&mut self, config: &Config) -> Option<Register> {
+        if let Some(text) = helper(&config.paste_command).and_then(|mut command| paste_with(&mut command)) {
+            let ours = self.register.as_ref().is_some_and(|register| register.system_text() == text);
+            if !ours && !text.is_empty() {
+                self.register = Some(Register { text, linewise: false });
+            }
+        }
+        self.register.clone()
+    }
+}
+
+// A helper like "xclip -selection clipboard", empty when there is none
+fn helper(line: &str) -> Option<Command> {
+    let mut words = line.split_whitespace();
+    let mut command = Command::new(words.next()?);
+    command.args(words);
+    Some(command)
+}
+
+// The helper must not write over the screen, so only its stdin is connected
+fn copy_with(command: &mut Command, text: &str) -> std::io::Result<()> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(std::io::Error::other(status.to_string()));
+    }
+    Ok(())
+}
+
+fn paste_with(command: &mut Command) -> Option<String> {
+    let output = command
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"))
+}
+
+// OSC 52 carries the text in base64
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (idx, byte)| n | (*byte as u32) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,296 @@
+use std::path::{Path, PathBuf};
+
+use crossterm::style::Color;
+use toml::{Table, Value};
+
+use crate::keymap::*;
+
+// Name of the file which overrides the user config for a project,
+// looked up in the current directory and the ones above it
+const PROJECT_CONFIG: &str = ".kilo.toml";
+
+// Colours used on screen, the [colors] table of the config
+#[derive(Copy, Clone)]
+pub struct Theme {
+    pub status_fg: Color,
+    pub status_bg: Color,
+    pub inactive_status_bg: Color,
+    pub line_number: Color,
+    pub current_line_number: Color,
+    pub comment: Color,
+    pub keyword1: Color,
+    pub keyword2: Color,
+    pub string: Color,
+    pub number: Color,
+    pub search_match: Color,
+    pub current_match: Color
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            status_fg: Color::White,
+            status_bg: Color::DarkMagenta,
+            inactive_status_bg: Color::DarkGrey,
+            line_number: Color::DarkGrey,
+            current_line_number: Color::Yellow,
+            comment: Color::Cyan,
+            keyword1: Color::Yellow,
+            keyword2: Color::Green,
+            string: Color::Magenta,
+            number: Color::Red,
+            search_match: Color::DarkBlue,
+            current_match: Color::DarkYellow
+        }
+    }
+}
+
+// How the gutter numbers the lines
+#[derive(Copy, Clone, PartialEq)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    // Distance from the line of the cursor, which keeps its own number
+    Relative
+}
+
+impl LineNumbers {
+    // The next mode, for toggling through them
+    pub fn next(self) -> Self {
+        match self {
+            LineNumbers::Off => LineNumbers::Absolute,
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Off
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineNumbers::Off => "off",
+            LineNumbers::Absolute => "absolute",
+            LineNumbers::Relative => "relative"
+        }
+    }
+}
+
+// Settings read from the config files at startup
+pub struct Config {
+    pub tab_width: usize,
+    // Tab key inserts spaces up to the next tab stop
+    pub expand_tabs: bool,
+    // Times the quit key has to be pressed again to quit with unsaved changes
+    pub quit_times: usize,
+    // Seconds a status message stays on screen
+    pub status_timeout: u64,
+    pub line_numbers: LineNumbers,
+    // Long lines go on over several screen lines instead of scrolling sideways
+    pub soft_wrap: bool,
+    // Wrapped lines break between words when they can
+    pub wrap_words: bool,
+    // Shown at the start of the screen lines a row goes on to
+    pub wrap_indicator: String,
+    // Modal editing like vi, starting in normal mode
+    pub vi_mode: bool,
+    // Copying also sets the terminal clipboard with OSC 52
+    pub osc52: bool,
+    // Helpers like "xclip -selection clipboard", the text goes to the stdin of
+    // the copy one and is read from the stdout of the paste one
+    pub copy_command: String,
+    pub paste_command: String,
+    // Where save-macros and load-macros keep the macros, macros.toml next
+    // to the user config if it is empty
+    pub macros_file: String,
+    pub theme: Theme,
+    pub keymap: Keymap
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tab_width: 8,
+            expand_tabs: false,
+            quit_times: 3,
+            status_timeout: 5,
+            line_numbers: LineNumbers::Off,
+            soft_wrap: false,
+            wrap_words: true,
+            wrap_indicator: String::from("↪ "),
+            vi_mode: false,
+            osc52: true,
+            copy_command: String::new(),
+            paste_command: String::new(),
+            macros_file: String::new(),
+            theme: Theme::default(),
+            keymap: Keymap::default()
+        }
+    }
+}
+
+impl Config {
+    // Function to read the user config and then the project one on top of it
+    // A broken setting keeps its default, and the first problem is returned
+    // to be shown in the status bar
+    pub fn load() -> (Self, Option<String>) {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+
+        for path in [user_config(), project_config()].into_iter().flatten() {
+            if let Err(err) = config.read_file(&path, &mut errors) {
+                errors.push(err);
+            }
+        }
+
+        let msg = errors.first().map(|first| match errors.len() {
+            1 => first.to_string(),
+            n => format!("{} (and {} more)", first, n - 1)
+        });
+        (config, msg)
+    }
+
+    // Function to apply one config file, errors of single settings are collected
+    // in errors while the file being unreadable as a whole is returned
+    fn read_file(&mut self, path: &Path, errors: &mut Vec<String>) -> Result<(), String> {
+        let name = path.display();
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Config {}: {}", name, err))?;
+        let table = text.parse::<Table>().map_err(|err| {
+            let line = err.span().map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+            format!("Config {} line {}: {}", name, line, err.message().lines().next().unwrap_or_default())
+        })?;
+
+        for (key, value) in &table {
+            if let Err(err) = self.set(key, value) {
+                errors.push(format!("Config {}: {}", name, err));
+            }
+        }
+        Ok(())
+    }
+
+    // Function to change a single setting
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        match key {
+            "tab_width" => self.tab_width = integer(key, value, 1, 32)? as usize,
+            "expand_tabs" => self.expand_tabs = boolean(key, value)?,
+            "quit_times" => self.quit_times = integer(key, value, 0, 100)? as usize,
+            "status_timeout" => self.status_timeout = integer(key, value, 1, 3600)? as u64,
+            // true or false, or the name of a mode
+            "line_numbers" => self.line_numbers = match value {
+                Value::Boolean(true) => LineNumbers::Absolute,
+                Value::Boolean(false) => LineNumbers::Off,
+                Value::String(name) if name == "absolute" => LineNumbers::Absolute,
+                Value::String(name) if name == "relative" => LineNumbers::Relative,
+                Value::String(name) if name == "off" => LineNumbers::Off,
+                _ => return Err(String::from("line_numbers must be true, false, \"absolute\", \"relative\" or \"off\""))
+            },
+            "soft_wrap" => self.soft_wrap = boolean(key, value)?,
+            "wrap_words" => self.wrap_words = boolean(key, value)?,
+            "wrap_indicator" => self.wrap_indicator = string(key, value)?,
+            "vi_mode" => self.vi_mode = boolean(key, value)?,
+            "osc52" => self.osc52 = boolean(key, value)?,
+            "copy_command" => self.copy_command = string(key, value)?,
+            "paste_command" => self.paste_command = string(key, value)?,
+            "macros_file" => self.macros_file = string(key, value)?,
+            "colors" => {
+                let Value::Table(colors) = value else {
+                    return Err(String::from("colors must be a table"));
+                };
+                for (name, color) in colors {
+                    self.set_color(name, color)?;
+                }
+            },
+            // Like "ctrl-g" = "find"
+            "keys" => {
+                let Value::Table(keys) = value else {
+                    return Err(String::from("keys must be a table"));
+                };
+                for (key, command) in keys {
+                    let command = command.as_str()
+                        .ok_or_else(|| format!("keys.{} must be the name of a command", key))?;
+                    self.keymap.bind(key, command)?;
+                }
+            },
+            _ => return Err(format!("unknown setting '{}'", key))
+        }
+        Ok(())
+    }
+
+    fn set_color(&mut self, name: &str, value: &Value) -> Result<(), String> {
+        let theme = &mut self.theme;
+        let slot = match name {
+            "status_fg" => &mut theme.status_fg,
+            "status_bg" => &mut theme.status_bg,
+            "inactive_status_bg" => &mut theme.inactive_status_bg,
+            "line_number" => &mut theme.line_number,
+            "current_line_number" => &mut theme.current_line_number,
+            "comment" => &mut theme.comment,
+            "keyword1" => &mut theme.keyword1,
+            "keyword2" => &mut theme.keyword2,
+            "string" => &mut theme.string,
+            "number" => &mut theme.number,
+            "match" => &mut theme.search_match,
+            "current_match" => &mut theme.current_match,
+            _ => return Err(format!("unknown colour '{}'", name))
+        };
+        *slot = parse_color(value)
+            .ok_or_else(|| format!("colors.{} must be a colour name, \"#rrggbb\" or 0-255", name))?;
+        Ok(())
+    }
+
+    // Function to find the file of macros_file, None without a home directory
+    pub fn macros_path(&self) -> Option<PathBuf> {
+        match self.macros_file.as_str() {
+            "" => Some(config_dir()?.join("macros.toml")),
+            file => Some(PathBuf::from(file))
+        }
+    }
+}
+
+// ~/.config/kilo, or under $XDG_CONFIG_HOME if that is set
+fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config")
+    };
+    Some(base.join("kilo"))
+}
+
+fn user_config() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml")).filter(|path| path.is_file())
+}
+
+// The nearest .kilo.toml from the current directory upwards
+fn project_config() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+fn integer(key: &str, value: &Value, min: i64, max: i64) -> Result<i64, String> {
+    match value {
+        Value::Integer(n) if (min..=max).contains(n) => Ok(*n),
+        _ => Err(format!("{} must be a number from {} to {}", key, min, max))
+    }
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool, String> {
+    value.as_bool().ok_or_else(|| format!("{} must be true or false", key))
+}
+
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    value.as_str().map(String::from).ok_or_else(|| format!("{} must be a string", key))
+}
+
+// A colour is a name like "dark_magenta", "#rrggbb" or an ANSI colour number
+fn parse_color(value: &Value) -> Option<Color> {
+    match value {
+        Value::Integer(n) => u8::try_from(*n).ok().map(Color::AnsiValue),
+        Value::String(name) if name.starts_with('#') && name.len() == 7 => {
+            let channel = |at: usize| u8::from_str_radix(name.get(at..at + 2)?, 16).ok();
+            Some(Color::Rgb { r: channel(1)?, g: channel(3)?, b: channel(5)? })
+        },
+        Value::String(name) if name == "reset" || name == "default" => Some(Color::Reset),
+        Value::String(name) => Color::try_from(name.as_str()).ok(),
+        _ => None
+    }
+}
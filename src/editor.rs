@@ -1,29 +1,51 @@
 use crossterm::event::{KeyCode, KeyModifiers, KeyEvent};
 
-//use crossterm::event::{Event::Mouse, MouseEventKind, MouseEvent};
-
-use std::io::{stdout, Write};
+use crossterm::event::{Event, MouseEventKind, MouseEvent, MouseButton};
 
 use crossterm::{terminal, Result};
 
 use errno::errno;
 
+use crate::backend::*;
 use crate::screen::*;
 use crate::keyboard::*;
 use crate::row::*;
+use crate::history::*;
+use crate::fileio::*;
+use crate::buffer::*;
+use crate::window::*;
+use crate::search::*;
+use crate::goto::*;
+use crate::ex::*;
+use crate::config::*;
+use crate::keymap::*;
+use crate::macros::*;
+use crate::vi::*;
+use crate::clipboard::*;
 
 use kilo_ed_rust::*;
 
-use std::path::Path;
-
 use std::time::{Instant, Duration};
 
-const KILO_QUIT_TIMES: usize = 3;
+use unicode_width::UnicodeWidthStr;
+
+// Rows one turn of the mouse wheel scrolls
+const MOUSE_SCROLL: i32 = 3;
+
+// Two clicks on the same spot within this time select a word
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+
+// Bytes a vi put with a count may add in one go
+const MAX_PUT: usize = 64 * 1024 * 1024;
 
 enum PromptKey {
     Enter,
     Escape,
-    Char(char),
+    Char,
+    // Any other Ctrl-key, for the callback to give it a meaning
+    Ctrl(char),
+    // For completing what is typed
+    Tab,
     Prev,
     Next
 }
@@ -33,233 +55,686 @@ enum PromptKey {
 // Types that are Copy should have a trivial implementation of Clone, hence both used.
 #[derive(Copy, Clone)] 
 enum EditorKey {
-    ArrowUp,
-    ArrowDown,
-    ArrowLeft,
-    ArrowRight
-}
-
-enum SearchDirection {
-    Forward,
-    Backward
+    Up,
+    Down,
+    Left,
+    Right
 }
 
 pub struct Editor {
     screen : Screen,
     keyboard : Keyboard,
     cursor : CursorPos,
-    rows : Vec<Row>,
     rowoff : u16,
     coloff : u16,
-    filename: String,
     status_time: Instant,
     status_msg: String,
     render_x: u16,
-    dirty: usize,
     quit_times: usize,
-    last_match: Option<usize>,
-    direction: SearchDirection
+    // Every match of the search query while it is being typed, and the one at the cursor
+    matches: Vec<Match>,
+    current_match: Option<usize>,
+    search: SearchOptions,
+    // Shown after the text typed in a prompt, set by the prompt callback
+    prompt_info: String,
+    // Replaces the text typed in a prompt, for the callback to complete it
+    prompt_text: Option<String>,
+    // Every open file, the one being edited is buffers[current]
+    buffers: Vec<Buffer>,
+    current: usize,
+    // Every split window, the one with the focus is windows[active]
+    windows: Vec<Window>,
+    active: usize,
+    layout: Layout,
+    config: Config,
+    vi: Vi,
+    // Where the selection started, it goes to the cursor
+    mark: Option<CursorPos>,
+    clipboard: Clipboard,
+    macros: Macros,
+    commands: CommandHistory,
+    // Where the left button went down, dragging selects from there
+    drag_from: Option<CursorPos>,
+    last_click: Option<(Instant, CursorPos)>
 }
 
 impl Editor {
-    // Function to open and read first line if the filename is passed
-    pub fn open_file<P: AsRef<Path> + ToString>(filename: P) -> Result<Self> {
-        let fn_filename = filename.to_string();
-        let lines = std::fs::read_to_string(filename)
-            .expect("Unable to open file")
-            .split('\n')
-            .map(|x| x.into()) 
-            .collect::<Vec<String>>();
-        Editor::build(&lines, fn_filename)
+    // Function to open every file passed on the command line, each in its own buffer
+    pub fn open_files(filenames: &[String]) -> Result<Self> {
+        // The config has to be in place before the files are read, tabs are rendered with it
+        let (config, config_msg) = Config::load();
+        let mut editor = Editor::build(Vec::new(), config, Box::new(Terminal), Box::new(Terminal))?;
+
+        for filename in filenames {
+            let (buffer, msg) = Buffer::open(filename);
+            if let Some(msg) = msg {
+                editor.set_status_msg(format!("{}: {}", filename, msg));
+            }
+            editor.buffers.push(buffer);
+        }
+        if editor.buffers.is_empty() {
+            editor.buffers.push(Buffer::new());
+        }
+        // Macros saved before are there to play again
+        if let Some(path) = editor.config.macros_path().filter(|path| path.is_file()) {
+            if let Err(err) = editor.macros.load(&path) {
+                editor.set_status_msg(format!("Can't load macros! {}", err));
+            }
+        }
+        // A broken config matters more than the files
+        if let Some(msg) = config_msg {
+            editor.set_status_msg(msg);
+        }
+        Ok(editor)
     }
 
     pub fn new() -> Result<Self> {
-        Editor::build(&[],"")
+        Editor::open_files(&[])
     }
     
-    fn build<T: Into<String>>(data: &[String], filename: T) -> Result<Self> {
+    fn build(buffers: Vec<Buffer>, config: Config, input: Box<dyn Input>, output: Box<dyn Output>) -> Result<Self> {
+        set_tab_stop(config.tab_width);
+
         Ok(Self {
-            screen : Screen::new()?,
-            keyboard : Keyboard {},
+            screen : Screen::new(config.theme, output)?,
+            keyboard : Keyboard::new(input),
             cursor : CursorPos::default(),  // Initially - at default position
-            rows : if data.is_empty() { Vec::new() } 
-                else { 
-                    let v = Vec::from(data);
-                    let mut rows = Vec::new();
-                    for row in v {
-                        rows.push(Row::new(row));
-                    }
-                    if rows.last().unwrap().len() == 0{
-                        rows.pop();
-                    }
-                    rows
-                },
             rowoff : 0,
             coloff : 0,
-            filename : filename.into(),
             status_time : Instant::now(), // Current time
-            status_msg : String::from("Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl-f to find"),
+            status_msg : config.keymap.help(),
             render_x : 0,
-            dirty: 0, 
-            quit_times: KILO_QUIT_TIMES,
-            last_match: None,
-            direction: SearchDirection::Forward
+            quit_times: config.quit_times,
+            matches: Vec::new(),
+            current_match: None,
+            search: SearchOptions::default(),
+            prompt_info: String::new(),
+            prompt_text: None,
+            buffers,
+            current: 0,
+            windows: vec![Window { buffer: 0, cursor: CursorPos::default(), rowoff: 0, coloff: 0 }],
+            active: 0,
+            layout: Layout::Window(0),
+            vi: Vi::new(config.vi_mode),
+            mark: None,
+            clipboard: Clipboard::default(),
+            macros: Macros::default(),
+            commands: CommandHistory::default(),
+            drag_from: None,
+            last_click: None,
+            config
         })
     }
+
+    fn buf(&self) -> &Buffer {
+        &self.buffers[self.current]
+    }
+
+    fn buf_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current]
+    }
+
+    fn rows(&self) -> &[Row] {
+        &self.buf().rows
+    }
     
     // Function to start the editor
     pub fn start(&mut self) -> Result<()> {
         terminal::enable_raw_mode()?;   // Step 5 - enabling raw mode during input
+        self.screen.capture_mouse(true)?;
+
+        self.run()?;
 
+        // To resolve error of Step 24
+        let _ = self.screen.clear();
+
+        self.screen.capture_mouse(false)?;
+        terminal::disable_raw_mode() // Step 6 - restoring the terminal mode after quitting
+    }
+
+    // Function to draw the screen and handle input until the editor quits or the input ends
+    fn run(&mut self) -> Result<()> {
         loop{
             if self.refresh_screen().is_err(){
                     self.die("Unable to refresh screen");
                 }
           
             if self.process_keypress()? {
-                break;
+                return Ok(());
             }
         }
-
-        // To resolve error of Step 24
-        let _ = self.screen.clear();
-
-        terminal::disable_raw_mode() // Step 6 - restoring the terminal mode after quitting
     }
 
     // Function to accept input till Ctrl-q is pressed
     // Waits for a keypress and then handles it.
     // Can check changes.rs for own definition
     pub fn process_keypress(&mut self) -> Result<bool> {
-        let bounds = self.screen.bounds();
-
-        if let Ok(c) = self.keyboard.read_key(){
-            match c {
-                // Ctrl-q to exit
-                KeyEvent {
-                    code: KeyCode::Char('q'),       
-                    modifiers: KeyModifiers::CONTROL,
-                } => {
-                    if self.dirty > 0 && self.quit_times > 0 {
-                        self.set_status_msg(format!("Warning!! File has unsaved changes. \
-                        Press Ctrl-q {} more times to quit", self.quit_times)); 
-                        self.quit_times -= 1;
-                        return Ok(false);
-                    } 
-                    else {
-                        return Ok(true);
-                    }
-                },
-                
-                // Inserting characters
-                KeyEvent {
-                    code : KeyCode::Char(key),
-                    modifiers : KeyModifiers::NONE | KeyModifiers::SHIFT 
-                } => self.editor_insert_char(key),
-
-                // Saving file 
-                KeyEvent {
-                    code : KeyCode::Char('s'),
-                    modifiers : KeyModifiers::CONTROL,
-                } => self.save(),
-
-                KeyEvent {
-                    code : KeyCode::Backspace,
-                    modifiers : KeyModifiers::NONE
-                } => self.editor_del_char(),
-
-                KeyEvent {
-                    code : KeyCode::Char('h'),
-                    modifiers : KeyModifiers::CONTROL
-                } => self.editor_del_char(),
-
-                KeyEvent {
-                    code : KeyCode::Delete,
-                    modifiers : KeyModifiers::NONE
-                } => {
-                        // Deletes the character under the cursor 
-                        self.move_cursor(EditorKey::ArrowRight);
-                        self.editor_del_char();
-                    },
-                
-                KeyEvent {
-                    code : KeyCode::Enter,
-                    modifiers : KeyModifiers::NONE
-                } => self.insert_new_line(),
-
-                // Find
-                KeyEvent {
-                    code : KeyCode::Char('f'),
-                    modifiers : KeyModifiers::CONTROL,
-                } => self.find(),
-
-
-                // Cursor movement through arrow keys
-                KeyEvent { code, modifiers : _ } => match code {
-                    KeyCode::Home => self.cursor.x = 0,
-                    KeyCode::End => 
-                        if self.cursor.y < self.rows.len() as u16 {
-                        self.cursor.x = self.rows[self.cursor.y as usize].len() as u16; },
-                    KeyCode::Up => self.move_cursor(EditorKey::ArrowUp),
-                    KeyCode::Down => self.move_cursor(EditorKey::ArrowDown),
-                    KeyCode::Left => self.move_cursor(EditorKey::ArrowLeft),
-                    KeyCode::Right => self.move_cursor(EditorKey::ArrowRight),
-                    KeyCode::PageUp | KeyCode::PageDown => {
-                        if code == KeyCode::PageUp {
-                            self.cursor.y = self.rowoff; }
-                        else {
-                            self.cursor.y = 
-                                (self.rowoff + bounds.y - 1).min(self.rows.len() as u16); }
-                        for _ in 0..bounds.y {
-                            self.move_cursor( if code == KeyCode::PageUp {EditorKey::ArrowUp}
-                                             else {EditorKey::ArrowDown} )
-                        }
-                    },
-                    _ => {}
-                },
+        match self.keyboard.read_event() {
+            Ok(Event::Key(key)) => return Ok(self.handle_key(key)),
+            Ok(Event::Mouse(event)) => {
+                self.handle_mouse(event);
+                return Ok(false);
+            },
+            // Drawn again in the new size right away, by start()
+            Ok(Event::Resize(columns, rows)) => {
+                self.screen.resize(columns, rows);
+                return Ok(false);
+            },
+            // Only scripted input ends
+            Err(ResultCode::InputEnded) => return Ok(true),
+            Err(ResultCode::KeyReadFail) => {}
+        }
+        self.die("Unable to read from keyboard");
+        Ok(false)
+    }
+
+    // Function to act on one key, returns true to quit the editor
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        if self.vi.mode != Mode::Insert {
+            return self.vi_key(key);
+        }
+        if self.vi.enabled {
+            if let Some(keys) = &mut self.vi.recording {
+                keys.push(key);
+            }
+            if key.code == KeyCode::Esc {
+                self.leave_insert();
+                return false;
+            }
+        }
+        self.run_key(key)
+    }
+
+    // Function to run the command a key is bound to, or else to type it
+    fn run_key(&mut self, key: KeyEvent) -> bool {
+        match self.config.keymap.lookup(&key) {
+            Some(command) => {
+                let quit = self.run_command(command);
+                if !command.keeps_selection() {
+                    self.mark = None;
+                    self.leave_visual();
+                }
+                if quit {
+                    return true;
+                }
+                if command == Command::Quit {
+                    return false;
+                }
+            },
+
+            // Inserting characters
+            None => if let KeyEvent {
+                code : KeyCode::Char(ch),
+                modifiers : KeyModifiers::NONE | KeyModifiers::SHIFT
+            } = key {
+                self.editor_insert_char(ch);
+                self.mark = None;
+            }
+        }
+        self.quit_times = self.config.quit_times;
+        false
+    }
+
+    // Function to do what a key is bound to, returns true to quit the editor
+    fn run_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Quit => return self.quit(),
+            Command::Save => self.save(),
+            Command::Find => self.find(),
+            Command::Replace => self.replace(),
+            Command::GoTo => self.go_to(),
+            Command::Undo => {
+                self.undo();
+            },
+            Command::Redo => {
+                self.redo();
+            },
+            Command::LineEnding => self.set_line_ending(),
+            Command::NextBuffer => self.next_buffer(),
+            Command::PrevBuffer => self.prev_buffer(),
+            Command::PickBuffer => self.pick_buffer(),
+            // Splitting windows, the next key says what to do
+            Command::Window => self.window_command(),
+            Command::NewLine => self.insert_new_line(),
+            Command::InsertTab => self.insert_tab(),
+            Command::DeleteBack => if !self.delete_selection() {
+                self.editor_del_char();
+            },
+            Command::DeleteForward => if !self.delete_selection() {
+                // Deletes the character under the cursor
+                self.move_cursor(EditorKey::Right);
+                self.editor_del_char();
+            },
+            Command::MoveLeft => self.move_cursor(EditorKey::Left),
+            Command::MoveRight => self.move_cursor(EditorKey::Right),
+            Command::MoveUp => self.move_cursor(EditorKey::Up),
+            Command::MoveDown => self.move_cursor(EditorKey::Down),
+            Command::LineStart => self.cursor.x = 0,
+            Command::LineEnd => {
+                if self.cursor.y < self.rows().len() as u16 {
+                    self.cursor.x = self.rows()[self.cursor.y as usize].len() as u16;
+                }
+            },
+            Command::PageUp => self.page(EditorKey::Up),
+            Command::PageDown => self.page(EditorKey::Down),
+            Command::SelectLeft => self.select(Command::MoveLeft),
+            Command::SelectRight => self.select(Command::MoveRight),
+            Command::SelectUp => self.select(Command::MoveUp),
+            Command::SelectDown => self.select(Command::MoveDown),
+            Command::SelectLineStart => self.select(Command::LineStart),
+            Command::SelectLineEnd => self.select(Command::LineEnd),
+            Command::SelectPageUp => self.select(Command::PageUp),
+            Command::SelectPageDown => self.select(Command::PageDown),
+            Command::Cut => self.cut(),
+            Command::Copy => self.copy(),
+            Command::Paste => self.paste(),
+            Command::LineNumbers => {
+                self.config.line_numbers = self.config.line_numbers.next();
+                self.set_status_msg(format!("Line numbers: {}", self.config.line_numbers.name()));
+            },
+            Command::SoftWrap => {
+                self.set_soft_wrap(!self.config.soft_wrap);
+                self.set_status_msg(format!("Soft wrap: {}", if self.config.soft_wrap { "on" } else { "off" }));
+            },
+            Command::RecordMacro => self.record_macro(),
+            Command::PlayMacro => self.play_macro(),
+            Command::SaveMacros => self.save_macros(),
+            Command::LoadMacros => self.load_macros(),
+            Command::Ex => return self.command_line()
+        }
+        false
+    }
+
+    // Function to move the cursor and select what it passes over
+    fn select(&mut self, movement: Command) {
+        let mark = self.mark.unwrap_or(self.cursor);
+        self.run_command(movement);
+        self.mark = Some(mark);
+    }
+
+    // The text of the selection, or else the line of the cursor as a whole line
+    fn copied_text(&self) -> Option<Register> {
+        if let Some((from, to)) = self.selection() {
+            return Some(Register { text: self.text_between(from, to), linewise: false });
+        }
+        self.rows()
+            .get(self.cursor.y as usize)
+            .map(|row| Register { text: row.characters.clone(), linewise: true })
+    }
+
+    fn copy(&mut self) {
+        if let Some(register) = self.copied_text() {
+            self.set_status_msg(format!("Copied {} bytes", register.text.len()));
+            self.store(register);
+        }
+    }
+
+    fn cut(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+        let Some(register) = self.copied_text() else {
+            return;
+        };
+
+        if register.linewise {
+            self.begin_step(false);
+            self.del_row(self.cursor.y as usize);
+            self.cursor.x = 0;
+            self.commit_step();
+        } else {
+            self.delete_selection();
+        }
+        self.set_status_msg(format!("Cut {} bytes", register.text.len()));
+        self.store(register);
+    }
+
+    // Function to paste the clipboard at the cursor, in place of the selection if there is one
+    // Whole lines go above the line of the cursor
+    fn paste(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+        let Some(register) = self.clipboard.load(&self.config) else {
+            self.set_status_msg(String::from("Nothing to paste"));
+            return;
+        };
+
+        self.begin_step(false);
+        self.delete_selection();
+        if self.cursor.y as usize == self.rows().len() {
+            self.insert_row(self.rows().len(), String::new());
+        }
+        let (at, text) = if register.linewise {
+            self.cursor.x = 0;
+            (TextPos { row: self.cursor.y as usize, byte: 0 }, register.text + "\n")
+        } else {
+            (self.text_pos(self.cursor), register.text)
+        };
+        self.do_edit(Edit::Insert { at, text: text.clone() });
+
+        // The cursor ends after what was pasted
+        let end = at.after(&text);
+        self.cursor.y = end.row as u16;
+        self.cursor.x = self.rows()[end.row].grapheme_index(end.byte) as u16;
+        self.commit_step();
+    }
+
+    // Function to act on the mouse: clicking places the cursor, dragging selects,
+    // a double click selects a word and the wheel scrolls
+    fn handle_mouse(&mut self, event: MouseEvent) {
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => self.click(event.column, event.row),
+            MouseEventKind::Drag(MouseButton::Left) => self.drag(event.column, event.row),
+            MouseEventKind::Up(MouseButton::Left) => self.drag_from = None,
+            MouseEventKind::ScrollUp => self.scroll_view(-MOUSE_SCROLL),
+            MouseEventKind::ScrollDown => self.scroll_view(MOUSE_SCROLL),
+            _ => {}
+        }
+    }
+
+    fn click(&mut self, column: u16, row: u16) {
+        let (windows, _) = self.arrange();
+        let Some(&(idx, area)) = windows.iter().find(|(_, area)| area.contains(column, row)) else {
+            return;
+        };
+        if idx != self.active {
+            self.focus_window(idx);
+        }
+        self.mark = None;
+        self.drag_from = None;
+        self.leave_visual();
+        // Clicking the status bar only gives the window the focus
+        if row + 1 >= area.y + area.height {
+            return;
+        }
+
+        let pos = self.position_at(column, row);
+        let double = self.last_click.is_some_and(|(time, at)| at == pos && time.elapsed() < DOUBLE_CLICK);
+        self.cursor = pos;
+        if double {
+            let (start, end) = self.rows().get(pos.y as usize).map_or((0, 0), |row| row.word_at(pos.x as usize));
+            self.mouse_select(CursorPos { x: start as u16, ..pos }, CursorPos { x: end as u16, ..pos });
+            self.last_click = None;
+        } else {
+            self.drag_from = Some(pos);
+            self.last_click = Some((Instant::now(), pos));
+        }
+    }
+
+    fn drag(&mut self, column: u16, row: u16) {
+        let Some(from) = self.drag_from else {
+            return;
+        };
+        let to = self.position_at(column, row);
+        self.mouse_select(from, to);
+    }
+
+    // Function to select from one position to another (excluded), in visual mode with vi
+    fn mouse_select(&mut self, from: CursorPos, to: CursorPos) {
+        if self.vi.mode == Mode::Insert {
+            self.mark = Some(from).filter(|from| *from != to);
+            self.cursor = to;
+            return;
+        }
+        // Visual mode takes in the character under the cursor
+        let (start, end) = ordered(from, to);
+        let end = if start != end && end.x > 0 { CursorPos { x: end.x - 1, ..end } } else { end };
+        (self.vi.anchor, self.cursor) = if from == start { (start, end) } else { (end, start) };
+        self.vi.mode = Mode::Visual;
+        self.vi_clamp();
+    }
+
+    // Text position shown at a place on the screen in the active window,
+    // above or below the text it is the row just outside of it so that dragging there scrolls
+    fn position_at(&self, column: u16, row: u16) -> CursorPos {
+        let area = self.text_area();
+        let column = column.saturating_sub(area.x) as usize;
+        let (y, rx) = match self.wrap() {
+            None => {
+                let y = if row < area.y {
+                    self.rowoff.saturating_sub(1)
+                } else if row >= area.y + area.height {
+                    self.rowoff + area.height
+                } else {
+                    self.rowoff + row - area.y
+                };
+                (y as usize, self.coloff as usize + column)
+            },
+            Some(wrap) => {
+                let top = (self.rowoff as usize, self.coloff as usize);
+                let (y, part) = if row < area.y {
+                    self.lines_back(&wrap, top, 1)
+                } else {
+                    self.lines_forward(&wrap, top, (row - area.y).min(area.height) as usize)
+                };
+                (y, self.wrapped_column(&wrap, y, part, column))
+            }
+        };
+
+        // Past the last row is only a place to type in without vi
+        let last = if self.vi.mode == Mode::Insert { self.rows().len() } else { self.rows().len().saturating_sub(1) };
+        let y = y.min(last) as u16;
+        let x = match self.rows().get(y as usize) {
+            Some(text) => text.renderx_to_cursorx(rx),
+            None => 0
+        };
 
+        let mut pos = CursorPos { x, y };
+        if self.vi.mode != Mode::Insert {
+            let row_len = self.rows().get(y as usize).map_or(0, |row| row.len());
+            pos.x = pos.x.min(row_len.saturating_sub(1) as u16);
+        }
+        pos
+    }
+
+    // Function to move the view by some rows, taking the cursor along when it would leave it
+    fn scroll_view(&mut self, delta: i32) {
+        let height = self.text_area().height.max(1);
+        if let Some(wrap) = self.wrap() {
+            // Counting screen lines, the cursor goes to the start of the first or last one in view
+            let top = (self.rowoff as usize, self.coloff as usize);
+            let top = if delta < 0 { self.lines_back(&wrap, top, delta.unsigned_abs() as usize) }
+                      else { self.lines_forward(&wrap, top, delta as usize) };
+            let top = if top.0 < self.rows().len() { top } else { (self.rows().len().saturating_sub(1), 0) };
+            let bottom = self.lines_forward(&wrap, top, height as usize - 1);
+            let here = (self.cursor.y as usize, self.cursor_line(&wrap).0);
+            let to = if here < top { Some(top) } else if here > bottom { Some(bottom) } else { None };
+            let to = to.map(|(row, part)| (row, self.wrapped_column(&wrap, row, part, 0)));
+
+            (self.rowoff, self.coloff) = (top.0 as u16, top.1 as u16);
+            if let Some((row, rx)) = to {
+                self.cursor.y = row as u16;
+                self.cursor.x = self.rows().get(row).map_or(0, |text| text.renderx_to_cursorx(rx));
             }
+        } else {
+            let last = self.rows().len().saturating_sub(1) as i32;
+            self.rowoff = (self.rowoff as i32 + delta).clamp(0, last.max(0)) as u16;
+            self.cursor.y = self.cursor.y.clamp(self.rowoff, self.rowoff + height - 1);
+        }
+        if self.vi.mode == Mode::Insert {
+            self.cursor.y = self.cursor.y.min(self.rows().len() as u16);
+            let row_len = self.rows().get(self.cursor.y as usize).map_or(0, |row| row.len());
+            self.cursor.x = self.cursor.x.min(row_len as u16);
+        } else {
+            self.vi_clamp();
+        }
+    }
+
+    // Function to put text in the clipboard, shared with the system one
+    fn store(&mut self, register: Register) {
+        if let Err(err) = self.clipboard.store(register, &self.config, &mut self.screen) {
+            self.set_status_msg(err);
+        }
+    }
+
+    // Function to delete the selected text, returns false if nothing is selected
+    fn delete_selection(&mut self) -> bool {
+        let Some((from, to)) = self.selection() else {
+            return false;
+        };
+        self.mark = None;
+        if self.is_read_only() {
+            return true;
+        }
+
+        let text = self.text_between(from, to);
+        self.do_edit(Edit::Delete { at: from, text });
+        self.cursor = CursorPos {
+            x : self.rows()[from.row].grapheme_index(from.byte) as u16,
+            y : from.row as u16
+        };
+        true
+    }
+
+    // Function to quit, unless there are unsaved changes and it wasn't asked often enough
+    fn quit(&mut self) -> bool {
+        let unsaved = self.buffers.iter().filter(|buffer| buffer.dirty > 0).count();
+        if unsaved > 0 && self.quit_times > 0 {
+            let what = if unsaved == 1 { String::from("File has") }
+                       else { format!("{} buffers have", unsaved) };
+            let key = self.config.keymap.key_for(Command::Quit).unwrap_or_else(|| String::from("the quit key"));
+            self.set_status_msg(format!("Warning!! {} unsaved changes. \
+            Press {} {} more times to quit", what, key, self.quit_times)); 
+            self.quit_times -= 1;
+            return false;
+        }
+        true
+    }
+
+    // Function to move the cursor a screen up or down
+    fn page(&mut self, key: EditorKey) {
+        let bounds = self.bounds();
+        if let Some(wrap) = self.wrap() {
+            // To the first or last screen line in view, then a screen full further
+            let top = (self.rowoff as usize, self.coloff as usize);
+            let (row, part) = match key {
+                EditorKey::Up => top,
+                _ => self.lines_forward(&wrap, top, bounds.y.saturating_sub(1) as usize)
+            };
+            let rx = self.wrapped_column(&wrap, row, part, 0);
+            self.cursor.y = row as u16;
+            self.cursor.x = self.rows().get(row).map_or(0, |text| text.renderx_to_cursorx(rx));
         }
+        else if let EditorKey::Up = key {
+            self.cursor.y = self.rowoff; }
         else {
-            self.die("Unable to read from keyboard");
+            self.cursor.y = 
+                (self.rowoff + bounds.y).saturating_sub(1).min(self.rows().len() as u16); }
+        for _ in 0..bounds.y {
+            self.move_cursor(key)
         }
-        self.quit_times = KILO_QUIT_TIMES;
-        Ok(false)
     }
 
     // Function to refresh the screen and move the cursor to top-left
     pub fn refresh_screen(&mut self) -> Result<()> {
-        let mut stdout = stdout();
+        if let Some((columns, rows)) = self.keyboard.take_resize() {
+            self.screen.resize(columns, rows);
+        }
+        if self.screen.too_small() {
+            return self.screen.draw_too_small();
+        }
         
         self.scroll();
-        self.screen.clear()?;
-        self.screen.draw_tildes(&self.rows, self.rowoff, self.coloff)?;
-        
-        let left_txt = format!("{:20} {} - {} lines", 
-                               if self.filename.is_empty(){"[No Name]"} else{&self.filename},
-                                if self.dirty > 0{"(modified)"} else{""},
-                                self.rows.len());
+        // Only the screen after the whole macro is worth drawing
+        if self.keyboard.has_queued() {
+            return Ok(());
+        }
+        self.screen.begin_frame();
+
+        let (windows, separators) = self.arrange();
+        for (idx, area) in &windows {
+            // The view of the active window is kept in the editor itself
+            let (buffer, cursor, rowoff, coloff) = if *idx == self.active {
+                (self.current, self.cursor, self.rowoff, self.coloff) }
+            else {
+                let window = &self.windows[*idx];
+                (window.buffer, window.cursor, window.rowoff, window.coloff) };
 
-        let right_txt = self.calc_percent();
+            let text = Rect { height: area.height.saturating_sub(1), ..*area };
+            let (matches, current_match, selection) = if *idx == self.active {
+                (self.matches.as_slice(), self.current_match, self.selection()) }
+            else {
+                (&[][..], None, None) };
+            let view = View {
+                rows: &self.buffers[buffer].rows,
+                rowoff,
+                coloff,
+                gutter: self.gutter_width(buffer),
+                numbers: self.config.line_numbers,
+                cursor_row: cursor.y as usize,
+                wrap: self.config.soft_wrap.then(|| Wrap {
+                    width: area.width,
+                    words: self.config.wrap_words,
+                    indicator: &self.config.wrap_indicator
+                }),
+                matches,
+                current_match,
+                selection
+            };
+            self.screen.draw_tildes(&text, &view)?;
+            if area.height > 0 {
+                let (mut left_txt, right_txt) = self.status_text(buffer, cursor);
+                if self.vi.enabled && *idx == self.active {
+                    left_txt = format!("{} | {}", self.vi.mode.name(), left_txt);
+                }
+                if let Some(register) = self.macros.recording.filter(|_| *idx == self.active) {
+                    left_txt = format!("recording @{} | {}", register, left_txt);
+                }
+                self.screen.draw_status_bar(&text, left_txt, right_txt, *idx == self.active)?;
+            }
+        }
+        for separator in &separators {
+            self.screen.draw_separator(separator)?;
+        }
         
-        if !self.status_msg.is_empty() && self.status_time.elapsed() > Duration::from_secs(5) {
+        if !self.status_msg.is_empty() && self.status_time.elapsed() > Duration::from_secs(self.config.status_timeout) {
             self.status_msg.clear();
         }
 
-        self.screen.draw_status_bar(left_txt, right_txt, self.status_msg.to_string())?;
-        
+        self.screen.draw_message_bar(self.status_msg.to_string())?;
 
-        self.screen.move_to(&self.cursor, self.render_x, self.rowoff, self.coloff)?;
+        let area = self.text_area();
+        let (x, y) = match self.wrap() {
+            None => (self.render_x - self.coloff, self.cursor.y - self.rowoff),
+            Some(wrap) => {
+                let (part, x) = self.cursor_line(&wrap);
+                let top = (self.rowoff as usize, self.coloff as usize);
+                (x as u16, self.count_lines(&wrap, top, (self.cursor.y as usize, part)) as u16)
+            }
+        };
+        self.screen.move_to(&area, x, y)?;
 
-        stdout.flush()
+        self.screen.present()
 
-//        stdout.queue(cursor::MoveTo(0,0))?.flush()
+    }
 
+    // Text of the status bar of a window showing the given buffer
+    fn status_text(&self, buffer: usize, cursor: CursorPos) -> (String, String) {
+        let buf = &self.buffers[buffer];
+        // Padded by the columns the name takes, which wide characters take two of
+        let name = buf.name();
+        let left_txt = format!("{}{}{} {}{} - {} lines | {} | {}{}{}", 
+                                if self.buffers.len() > 1 {
+                                    format!("[{}/{}] ", buffer + 1, self.buffers.len()) }
+                                else { String::new() },
+                                name,
+                                " ".repeat(20usize.saturating_sub(name.width())),
+                                if buf.dirty > 0{"(modified)"} else{""},
+                                if buf.read_only{"[RO]"} else{""},
+                                buf.rows.len(),
+                                buf.encoding.name(),
+                                buf.line_ending.name(),
+                                if buf.mixed_endings{"*"} else{""},
+                                if buf.final_newline{""} else{" [noeol]"});
+
+        (left_txt, self.calc_percent(buf, cursor))
     }
 
     // Function to exit the program
     pub fn die<S: Into<String>>(&mut self, message: S) {
         let _= self.screen.clear();
+        let _ = self.screen.capture_mouse(false);
         let _ = terminal::disable_raw_mode();
         eprintln!("{}: {}", message.into(), errno());
         std::process::exit(1);
@@ -270,54 +745,67 @@ impl Editor {
     fn move_cursor(&mut self, key : EditorKey) {
        use EditorKey::*;
 
-       let row_index = if self.cursor.y as usize > self.rows.len() {
+       let row_index = if self.cursor.y as usize > self.rows().len() {
                             None }
                        else {
                             Some(self.cursor.y as usize) };
 
         match key {
-            ArrowLeft => { 
+            Left => { 
                 if self.cursor.x != 0  {
                     self.cursor.x = self.cursor.x.saturating_sub(1)
                 }
                 else if self.cursor.y > 0 { 
                     self.cursor.y = self.cursor.y.saturating_sub(1);
-                    self.cursor.x = self.rows[self.cursor.y as usize].len() as u16
+                    self.cursor.x = self.rows()[self.cursor.y as usize].len() as u16
                 }
             }, 
-            ArrowRight => {
+            Right => {
                 if let Some(idx) = row_index {
-                    if (self.cursor.x as usize) < self.rows[idx].len() {
+                    if (self.cursor.x as usize) < self.rows()[idx].len() {
                         self.cursor.x += 1; }
-                    else if (self.cursor.x as usize) == self.rows[idx].len() {
+                    else if (self.cursor.x as usize) == self.rows()[idx].len() {
                         self.cursor.y += 1;
                         self.cursor.x = 0
                     };
                 }
             },
             //{ self.cursor.x = self.cursor.x.saturating_add(1) },
-            ArrowUp => { self.cursor.y = self.cursor.y.saturating_sub(1) },
-            ArrowDown => if (self.cursor.y as usize) < self.rows.len() { 
+            // Going by screen lines when rows wrap
+            Up | Down if self.config.soft_wrap => self.move_wrapped(key),
+            Up => { self.cursor.y = self.cursor.y.saturating_sub(1) },
+            Down => if (self.cursor.y as usize) < self.rows().len() { 
                             self.cursor.y += 1; }
         }
 
-        let rowlen = if self.cursor.y as usize >= self.rows.len() {
+        let rowlen = if self.cursor.y as usize >= self.rows().len() {
                         0 }
                      else {
-                         self.rows[self.cursor.y as usize].len() };
+                         self.rows()[self.cursor.y as usize].len() };
 
         self.cursor.x = self.cursor.x.min(rowlen as u16);
     }
 
     // Function for scrolling 
     fn scroll(&mut self) {
-        let bounds = self.screen.bounds();
+        let bounds = self.bounds();
+        // A window too small to show any text still has to keep the cursor in view
+        let bounds = CursorPos { x: bounds.x.max(1), y: bounds.y.max(1) };
         
-        self.render_x = if self.cursor.y < self.rows.len() as u16 {
-            self.rows[self.cursor.y as usize].cursorx_to_renderx(self.cursor.x) }
+        self.render_x = if self.cursor.y < self.rows().len() as u16 {
+            self.rows()[self.cursor.y as usize].cursorx_to_renderx(self.cursor.x) }
         else {
             0 };
 
+        if let Some(wrap) = self.wrap() {
+            let (rowoff, coloff) = self.wrapped_view(&wrap, bounds.y as usize);
+            (self.rowoff, self.coloff) = (rowoff as u16, coloff as u16);
+            return;
+        }
+
+        // No empty space is left below the last row when the window got taller
+        self.rowoff = self.rowoff.min((self.rows().len() as u16).saturating_sub(bounds.y));
+
         // Vertical scrolling
         if self.cursor.y < self.rowoff {
             self.rowoff = self.cursor.y; }
@@ -332,130 +820,805 @@ impl Editor {
             self.coloff = self.render_x - bounds.x + 1; }
     }
     
-    fn calc_percent(&self) -> String {
-        let percent = if self.rows.len() > 0 {
-            (self.cursor.y as usize * 100)/self.rows.len() }
+    // Where the view starts so that the screen line of the cursor is in it when rows wrap,
+    // as the row at rowoff and the first of its screen lines shown, which is kept in coloff
+    fn wrapped_view(&self, wrap: &Wrap, height: usize) -> (usize, usize) {
+        let rowoff = (self.rowoff as usize).min(self.rows().len());
+        let coloff = (self.coloff as usize).min(self.screen_lines(wrap, rowoff).len() - 1);
+        let top = (rowoff, coloff);
+        let here = (self.cursor.y as usize, self.cursor_line(wrap).0);
+
+        // The lowest the view can start with the cursor still on its last line
+        let lowest = self.lines_back(wrap, here, height - 1);
+        if here < top { here } else { top.max(lowest) }
+    }
+
+    // How rows wrap in the active window, None without soft wrap
+    fn wrap(&self) -> Option<Wrap<'_>> {
+        self.config.soft_wrap.then(|| Wrap {
+            width: self.text_area().width,
+            words: self.config.wrap_words,
+            indicator: &self.config.wrap_indicator
+        })
+    }
+
+    // The screen lines of a row of the buffer, past the last row there is one empty line
+    fn screen_lines(&self, wrap: &Wrap, row: usize) -> Vec<(usize, usize)> {
+        self.rows().get(row).map_or(vec![(0, 0)], |text| wrap.lines(text))
+    }
+
+    // Which screen line of its row the cursor is on and the column it is at there
+    fn cursor_line(&self, wrap: &Wrap) -> (usize, usize) {
+        let row = self.cursor.y as usize;
+        let rx = self.rows().get(row).map_or(0, |text| text.cursorx_to_renderx(self.cursor.x) as usize);
+        let lines = self.screen_lines(wrap, row);
+        let part = lines.iter().rposition(|(start, _)| *start <= rx).unwrap_or(0);
+        let indent = if part > 0 { wrap.indent() } else { 0 };
+        (part, rx - lines[part].0 + indent)
+    }
+
+    // Render column of a row shown at a column of one of its screen lines,
+    // which stays on that line unless it is the last one
+    fn wrapped_column(&self, wrap: &Wrap, row: usize, part: usize, column: usize) -> usize {
+        let lines = self.screen_lines(wrap, row);
+        let (start, end) = lines[part.min(lines.len() - 1)];
+        let indent = if part > 0 { wrap.indent() } else { 0 };
+        let rx = start + column.saturating_sub(indent);
+        if part + 1 < lines.len() { rx.min(end.saturating_sub(1)) } else { rx }
+    }
+
+    // The screen line n lines above a row and screen line of it, or the first one
+    fn lines_back(&self, wrap: &Wrap, (mut row, mut part): (usize, usize), n: usize) -> (usize, usize) {
+        for _ in 0..n {
+            if part > 0 {
+                part -= 1;
+            } else if row > 0 {
+                row -= 1;
+                part = self.screen_lines(wrap, row).len() - 1;
+            } else {
+                break;
+            }
+        }
+        (row, part)
+    }
+
+    // The screen line n lines below, at most the line after the last row
+    fn lines_forward(&self, wrap: &Wrap, (mut row, mut part): (usize, usize), n: usize) -> (usize, usize) {
+        for _ in 0..n {
+            if part + 1 < self.screen_lines(wrap, row).len() {
+                part += 1;
+            } else if row < self.rows().len() {
+                row += 1;
+                part = 0;
+            } else {
+                break;
+            }
+        }
+        (row, part)
+    }
+
+    // Number of screen lines from one to another further down
+    fn count_lines(&self, wrap: &Wrap, mut from: (usize, usize), to: (usize, usize)) -> usize {
+        let mut count = 0;
+        while from < to {
+            let next = self.lines_forward(wrap, from, 1);
+            if next == from {
+                break;
+            }
+            from = next;
+            count += 1;
+        }
+        count
+    }
+
+    // Function to move the cursor one screen line up or down, keeping its column on screen
+    fn move_wrapped(&mut self, key: EditorKey) {
+        let Some(wrap) = self.wrap() else {
+            return;
+        };
+        let (part, column) = self.cursor_line(&wrap);
+        let here = (self.cursor.y as usize, part);
+        let (row, part) = match key {
+            EditorKey::Up => self.lines_back(&wrap, here, 1),
+            _ => self.lines_forward(&wrap, here, 1)
+        };
+        if (row, part) == here {
+            return;
+        }
+        let rx = self.wrapped_column(&wrap, row, part, column);
+        self.cursor.y = row as u16;
+        self.cursor.x = self.rows().get(row).map_or(0, |text| text.renderx_to_cursorx(rx));
+    }
+
+    fn calc_percent(&self, buf: &Buffer, cursor: CursorPos) -> String {
+        let percent = if !buf.rows.is_empty() {
+            (cursor.y as usize * 100)/buf.rows.len() }
             else {
                 0
             };
         
-        let mut right_txt = format!("{},{}      {}%", self.cursor.y, self.cursor.x, percent);
+        let mut right_txt = format!("{},{}      {}%", cursor.y, cursor.x, percent);
  
-        if self.rows.len() == 0 { 
-                right_txt = format!("{},{}        All", self.cursor.y, self.cursor.x); }
+        if buf.rows.is_empty() { 
+                right_txt = format!("{},{}        All", cursor.y, cursor.x); }
         else if percent < 5 { 
-                right_txt = format!("{},{}      TOP", self.cursor.y, self.cursor.x); }
+                right_txt = format!("{},{}      TOP", cursor.y, cursor.x); }
         else if percent > 95 {
-            right_txt = format!("{},{}      BOT", self.cursor.y, self.cursor.x);
+            right_txt = format!("{},{}      BOT", cursor.y, cursor.x);
         }
         
-        right_txt
+        let filetype = buf.syntax.map_or("no ft", |syntax| syntax.filetype);
+        format!("{} | {}", filetype, right_txt)
     }
     
 
     fn editor_insert_char(&mut self, c: char) {
-        if self.cursor.y as usize == self.rows.len() {
-            self.insert_row(self.rows.len(), String::new());
-        }
-        self.rows[self.cursor.y as usize].row_insert_char(self.cursor.x as usize, c);
-        self.cursor.x += 1;
-        self.dirty += 1;
-    }
-
-    fn insert_row(&mut self, at: usize, s: String){
-        if at > self.rows.len() {
+        if self.is_read_only() {
             return;
         }
 
-        self.rows.insert(at, Row::new(s));
-        self.dirty += 1;
-    }
-
-    fn row_to_string(&self) -> String {
-        let mut data = String::new();
+        // Consecutive typing is undone in one go
+        self.begin_step(true);
+        self.delete_selection();
 
-        for row in &self.rows {
-            println!("{:?}", &row.characters);
-            data.push_str(&row.characters);
-            data.push('\n');
+        if self.cursor.y as usize == self.rows().len() {
+            self.insert_row(self.rows().len(), String::new());
         }
+        let at = TextPos {
+            row: self.cursor.y as usize,
+            byte: self.rows()[self.cursor.y as usize].byte_index(self.cursor.x as usize)
+        };
+        self.do_edit(Edit::Insert { at, text: c.to_string() });
 
-        data
+        // A combining mark joins the character before it, so the cursor
+        // doesn't always move by one
+        self.cursor.x = self.rows()[at.row].grapheme_index(at.byte + c.len_utf8()) as u16;
+
+        self.commit_step();
     }
 
-    fn save(&mut self) {
-        if self.filename.is_empty() {
-            if let Some(filename) = self.prompt("Save as (ESC to cancel)", None){
-                self.filename = filename;
-            } else {
-                self.set_status_msg(String::from("Save aborted"));
-                return;
-            }
+    // Function to insert a tab, or spaces up to the next tab stop if the config says so
+    fn insert_tab(&mut self) {
+        if !self.config.expand_tabs {
+            self.editor_insert_char('\t');
+            return;
         }
-       
-        let buf = self.row_to_string(); 
-        let len = buf.as_bytes().len();
-        if std::fs::write(&self.filename, &buf).is_ok() {
-            self.dirty = 0;
-            self.set_status_msg(format!("{:?} bytes written to disk successfully", len));
+
+        let render_x = self.rows()
+            .get(self.cursor.y as usize)
+            .map_or(0, |row| row.cursorx_to_renderx(self.cursor.x) as usize);
+        for _ in 0..tab_stop() - render_x % tab_stop() {
+            self.editor_insert_char(' ');
         }
-        else {
-            self.set_status_msg(format!("Can't save! I/O error: {}", errno()));
+    }
+
+    fn insert_row(&mut self, at: usize, s: String){
+        if at > self.rows().len() {
+            return;
         }
 
+        self.do_edit(Edit::InsertRow { at, text: s });
     }
-    
 
-    fn set_status_msg(&mut self, message: String) {
-        self.status_time = Instant::now();
-        self.status_msg = message;
+    // Function to make a change to the rows of the current buffer
+    fn do_edit(&mut self, edit: Edit) {
+        let cursor = self.cursor;
+        self.buf_mut().do_edit(edit, cursor);
     }
 
-    // Function to delete character left of the cursor from the screen
-    fn editor_del_char(&mut self) {
-        if self.cursor.y as usize == self.rows.len() {
-            return;
-        }
+    // Starts an undo step, every edit till commit_step() is undone together
+    fn begin_step(&mut self, typing: bool) {
+        let cursor = self.cursor;
+        self.buf_mut().history.begin(cursor, typing);
+    }
 
-        if self.cursor.x as usize == 0 && self.cursor.y == 0 {
-            return;
+    fn commit_step(&mut self) {
+        let cursor = self.cursor;
+        self.buf_mut().history.commit(cursor);
+    }
+
+    // Returns false when there was nothing to undo
+    fn undo(&mut self) -> bool {
+        if self.is_read_only() {
+            return false;
         }
 
-        if self.cursor.x > 0 
-            && self.rows[self.cursor.y as usize].del_char(self.cursor.x as usize - 1) {
-                self.cursor.x -= 1;
-                self.dirty += 1;
+        if let Some(cursor) = self.buf_mut().undo() {
+            self.cursor = cursor;
+            true
         } else {
-            self.cursor.x = self.rows[self.cursor.y as usize - 1].len() as u16;
-            if let Some(row) = self.del_row(self.cursor.y as usize) {
-                self.rows[self.cursor.y as usize - 1].append_string(&row);
-                self.cursor.y -= 1;
-                self.dirty += 1;
-            }
+            self.set_status_msg(String::from("Nothing to undo"));
+            false
         }
-        
     }
-    
-    // Function to delete a row and return its contents
-    fn del_row(&mut self, at: usize) -> Option<String>{
-        if at >= self.rows.len() {
-            None
-        } else {
-            self.dirty += 1;   
-            Some(self.rows.remove(at).characters)
+
+    fn redo(&mut self) -> bool {
+        if self.is_read_only() {
+            return false;
         }
 
+        if let Some(cursor) = self.buf_mut().redo() {
+            self.cursor = cursor;
+            true
+        } else {
+            self.set_status_msg(String::from("Nothing to redo"));
+            false
+        }
     }
 
-    fn insert_new_line(&mut self){
-        if self.cursor.x == 0 {
+    // Function to jump to a line, a column or a place in the file typed at the prompt
+    fn go_to(&mut self) {
+        let Some(text) = self.prompt("Go to line[:col], +N, -N, N% or bN for a byte (ESC to cancel)", None) else {
+            return;
+        };
+        match Target::parse(&text).and_then(|target| self.target_pos(target)) {
+            Ok(pos) => self.jump_to(pos),
+            Err(err) => self.set_status_msg(err)
+        }
+    }
+
+    // Function to find where a target is in the buffer
+    fn target_pos(&self, target: Target) -> EditorResult<CursorPos, String> {
+        let last = self.rows().len().max(1);
+        let row_len = |y: usize| self.rows().get(y).map_or(0, |row| row.len());
+
+        let (y, x) = match target {
+            Target::Line(line, column) => {
+                if line > last {
+                    return Err(format!("Line {} is past the end, the last one is {}", line, last));
+                }
+                let x = match column {
+                    Some(column) if column > row_len(line - 1) + 1 => {
+                        return Err(format!("Line {} has only {} columns", line, row_len(line - 1)));
+                    },
+                    Some(column) => column - 1,
+                    None => 0
+                };
+                (line - 1, x)
+            },
+            Target::Relative(count) => {
+                match (self.cursor.y as isize).checked_add(count).filter(|line| (0..last as isize).contains(line)) {
+                    Some(line) => (line as usize, 0),
+                    None => {
+                        let line = self.cursor.y as i128 + count as i128 + 1;
+                        return Err(format!("Line {} is out of range, the lines are 1 to {}", line, last));
+                    }
+                }
+            },
+            Target::Percent(percent) => ((last - 1) * percent / 100, 0),
+            Target::Byte(offset) => {
+                // The rows are as long as in the file, with its line endings between them,
+                // and an offset inside a line ending is the end of its row
+                let ending = self.buf().line_ending.as_str().len();
+                let mut start = 0;
+                let found = self.rows().iter().enumerate().find_map(|(y, row)| {
+                    let end = start + row.characters.len();
+                    let found = (offset < end + ending || offset == end)
+                        .then(|| (y, row.grapheme_index(offset.min(end) - start)));
+                    start = end + ending;
+                    found
+                });
+                match found {
+                    Some(pos) => pos,
+                    None if self.rows().is_empty() && offset == 0 => (0, 0),
+                    None => return Err(format!("Byte {} is past the end of the file", offset))
+                }
+            }
+        };
+        Ok(CursorPos { x: x as u16, y: y as u16 })
+    }
+
+    // Function to put the cursor somewhere and show it in the middle of the window
+    fn jump_to(&mut self, pos: CursorPos) {
+        self.cursor = pos;
+        if self.vi.mode != Mode::Insert {
+            self.vi_clamp();
+        }
+
+        let half = self.bounds().y as usize / 2;
+        let (rowoff, coloff) = match self.wrap() {
+            None => ((self.cursor.y as usize).saturating_sub(half), 0),
+            Some(wrap) => {
+                let here = (self.cursor.y as usize, self.cursor_line(&wrap).0);
+                self.lines_back(&wrap, here, half)
+            }
+        };
+        self.rowoff = rowoff as u16;
+        if self.config.soft_wrap {
+            self.coloff = coloff as u16;
+        }
+    }
+
+    // Function to start recording a macro into the register of the next key,
+    // or to stop the recording going on
+    fn record_macro(&mut self) {
+        if self.macros.recording.is_some() {
+            self.stop_macro();
+            return;
+        }
+        self.set_status_msg(String::from("Record macro into register (a-z, 0-9):"));
+        let _ = self.refresh_screen();
+
+        let Ok(key) = self.keyboard.read_key() else {
+            return;
+        };
+        self.set_status_msg(String::new());
+        match key.code {
+            KeyCode::Char(register) if is_register(register) => self.start_macro(register),
+            KeyCode::Esc => {},
+            _ => self.set_status_msg(String::from("Not a register"))
+        }
+    }
+
+    fn start_macro(&mut self, register: char) {
+        // A macro can't record another one
+        if self.keyboard.playing() {
+            return;
+        }
+        self.keyboard.start_recording();
+        self.macros.recording = Some(register);
+        self.set_status_msg(format!("Recording @{}", register));
+    }
+
+    fn stop_macro(&mut self) {
+        if self.keyboard.playing() {
+            return;
+        }
+        let Some(register) = self.macros.recording.take() else {
+            return;
+        };
+        let keys = self.keyboard.stop_recording();
+        self.set_status_msg(format!("Recorded {} keys into @{}", keys.len(), register));
+        self.macros.set(register, keys);
+    }
+
+    // Function to ask for a register and how many times to play it, like "a 10"
+    fn play_macro(&mut self) {
+        let Some(answer) = self.prompt("Play macro (register and times, empty for the last one)", None) else {
+            return;
+        };
+        let mut parts = answer.split_whitespace();
+        let register = match parts.next().map(|name| name.chars().collect::<Vec<char>>()) {
+            None => None,
+            Some(name) => match name[..] {
+                [register] if is_register(register) => Some(register),
+                _ => {
+                    self.set_status_msg(format!("Not a register: {}", answer.trim()));
+                    return;
+                }
+            }
+        };
+        let times = match parts.next().map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(times)) if times > 0 => times,
+            Some(_) => {
+                self.set_status_msg(format!("Not a number of times: {}", answer.trim()));
+                return;
+            }
+        };
+        self.play(register, times);
+    }
+
+    // Function to play the keys of a register, or of the one played last
+    fn play(&mut self, register: Option<char>, times: usize) {
+        let Some(register) = register.or(self.macros.last) else {
+            self.set_status_msg(String::from("No macro played yet"));
+            return;
+        };
+        if self.macros.recording == Some(register) {
+            self.set_status_msg(format!("Can't play @{} while recording it", register));
+            return;
+        }
+        let Some(keys) = self.macros.get(register) else {
+            self.set_status_msg(format!("Register @{} is empty", register));
+            return;
+        };
+        if let Err(err) = self.keyboard.play(keys, times) {
+            self.set_status_msg(format!("Macro stopped! {}", err));
+            return;
+        }
+        self.macros.last = Some(register);
+    }
+
+    fn save_macros(&mut self) {
+        let Some(path) = self.config.macros_path() else {
+            self.set_status_msg(String::from("No home directory to save the macros in"));
+            return;
+        };
+        match self.macros.save(&path) {
+            Ok(count) => self.set_status_msg(format!("{} macros saved to {}", count, path.display())),
+            Err(err) => self.set_status_msg(format!("Can't save macros! {}", err))
+        }
+    }
+
+    fn load_macros(&mut self) {
+        let Some(path) = self.config.macros_path() else {
+            self.set_status_msg(String::from("No home directory to load the macros from"));
+            return;
+        };
+        match self.macros.load(&path) {
+            Ok(count) => self.set_status_msg(format!("{} macros loaded from {}", count, path.display())),
+            Err(err) => self.set_status_msg(format!("Can't load macros! {}", err))
+        }
+    }
+
+    // Function to convert the file to another line ending style
+    fn set_line_ending(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let Some(name) = self.prompt("Line endings lf/crlf/cr (ESC to cancel)", None) else {
+            return;
+        };
+
+        match LineEnding::from_name(&name) {
+            Some(ending) if ending == self.buf().line_ending && !self.buf().mixed_endings => {
+                self.set_status_msg(format!("Line endings are already {}", ending.name()));
+            },
+            Some(ending) => {
+                let buf = self.buf_mut();
+                buf.line_ending = ending;
+                buf.mixed_endings = false;
+                buf.dirty += 1;
+                // Undoing edits can't bring back the saved file anymore
+                buf.history.forget_saved();
+                self.set_status_msg(format!("Line endings set to {}", ending.name()));
+            },
+            None => self.set_status_msg(format!("Unknown line ending: {}", name))
+        }
+    }
+
+    fn save(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        if self.buf().filename.is_empty() {
+            if let Some(filename) = self.prompt("Save as (ESC to cancel)", None){
+                self.buf_mut().set_filename(filename);
+            } else {
+                self.set_status_msg(String::from("Save aborted"));
+                return;
+            }
+        }
+        if !self.confirm_endings() {
+            self.set_status_msg(String::from("Save aborted"));
+            return;
+        }
+       
+        match self.buf_mut().save() {
+            Ok(len) => self.set_status_msg(format!("{:?} bytes written to disk successfully", len)),
+            Err(err) => self.set_status_msg(format!("Can't save! {}", err))
+        }
+
+    }
+
+    // Function to ask before a file with mixed line endings is written with only one of them
+    // Returns true to go on with saving
+    fn confirm_endings(&mut self) -> bool {
+        if !self.buf().mixed_endings {
+            return true;
+        }
+        let ending = self.buf().line_ending.name();
+        self.set_status_msg(format!("Mixed line endings will all be {}, save? (y/n)", ending));
+        loop {
+            let _ = self.refresh_screen();
+            match self.keyboard.read_key() {
+                Ok(KeyEvent { code : KeyCode::Char('y'), .. }) => return true,
+                Ok(KeyEvent { code : KeyCode::Char('n') | KeyCode::Esc, .. }) | Err(_) => return false,
+                _ => {}
+            }
+        }
+    }
+
+    // Function to show another buffer, the one we leave keeps its cursor and scroll
+    fn switch_buffer(&mut self, idx: usize) {
+        let (cursor, rowoff, coloff) = (self.cursor, self.rowoff, self.coloff);
+        let buf = self.buf_mut();
+        buf.cursor = cursor;
+        buf.rowoff = rowoff;
+        buf.coloff = coloff;
+
+        self.current = idx;
+        let buf = self.buf();
+        (self.cursor, self.rowoff, self.coloff) = (buf.cursor, buf.rowoff, buf.coloff);
+        self.leave_visual();
+        self.set_status_msg(format!("Buffer {}/{}: {}", idx + 1, self.buffers.len(), self.buf().name()));
+    }
+
+    fn next_buffer(&mut self) {
+        self.switch_buffer((self.current + 1) % self.buffers.len());
+    }
+
+    fn prev_buffer(&mut self) {
+        self.switch_buffer((self.current + self.buffers.len() - 1) % self.buffers.len());
+    }
+
+    // Function to pick a buffer from the list, by its number or a part of its name
+    fn pick_buffer(&mut self) {
+        let list = self.buffers
+            .iter()
+            .enumerate()
+            .map(|(idx, buffer)| format!("{}:{}{}", idx + 1, buffer.name(),
+                                         if buffer.dirty > 0 {"+"} else {""}))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let Some(choice) = self.prompt(&format!("Buffers {} (ESC to cancel)", list), None) else {
+            return;
+        };
+        let choice = choice.trim();
+
+        let idx = match choice.parse::<usize>() {
+            Ok(num) if num >= 1 && num <= self.buffers.len() => Some(num - 1),
+            _ => self.buffers.iter().position(|buffer| buffer.name().contains(choice))
+        };
+
+        match idx {
+            Some(idx) if !choice.is_empty() => self.switch_buffer(idx),
+            _ => self.set_status_msg(format!("No such buffer: {}", choice))
+        }
+    }
+    
+    // Function to find where every window and separator is on the screen
+    fn arrange(&self) -> (Vec<(usize, Rect)>, Vec<Rect>) {
+        let mut windows = Vec::new();
+        let mut separators = Vec::new();
+        self.layout.arrange(self.screen.area(), &mut windows, &mut separators);
+        (windows, separators)
+    }
+
+    // Text area of the window with the focus, without its status bar and line numbers
+    fn text_area(&self) -> Rect {
+        let (windows, _) = self.arrange();
+        let gutter = self.gutter_width(self.current);
+        windows
+            .iter()
+            .find(|(idx, _)| *idx == self.active)
+            .map(|(_, area)| {
+                // Same as draw_tildes(), which drops the line numbers if nothing else fits
+                let gutter = if gutter < area.width { gutter } else { 0 };
+                Rect {
+                    x : area.x + gutter,
+                    width : area.width - gutter,
+                    height : area.height.saturating_sub(1),
+                    ..*area
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    // Columns taken by the line numbers of a buffer, enough for the last one and a space
+    fn gutter_width(&self, buffer: usize) -> u16 {
+        if self.config.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        self.buffers[buffer].rows.len().max(1).to_string().len() as u16 + 1
+    }
+
+    // Function to know the height and width of the text area of the window
+    fn bounds(&self) -> CursorPos {
+        let area = self.text_area();
+        CursorPos {
+            x : area.width,
+            y : area.height
+        }
+    }
+
+    // Function to read the key after Ctrl-w and act on the windows
+    fn window_command(&mut self) {
+        self.set_status_msg(String::from("Window: s split | v vsplit | c close | w/arrows focus | +/- height | </> width"));
+        let _ = self.refresh_screen();
+
+        let Ok(key) = self.keyboard.read_key() else {
+            return;
+        };
+        self.set_status_msg(String::new());
+
+        match key.code {
+            KeyCode::Char('s') => self.split_window(SplitDir::Horizontal),
+            KeyCode::Char('v') => self.split_window(SplitDir::Vertical),
+            KeyCode::Char('c') | KeyCode::Char('q') => self.close_window(),
+            KeyCode::Char('w') => self.focus_window((self.active + 1) % self.windows.len()),
+            KeyCode::Up | KeyCode::Char('k') => self.focus_towards(EditorKey::Up),
+            KeyCode::Down | KeyCode::Char('j') => self.focus_towards(EditorKey::Down),
+            KeyCode::Left | KeyCode::Char('h') => self.focus_towards(EditorKey::Left),
+            KeyCode::Right | KeyCode::Char('l') => self.focus_towards(EditorKey::Right),
+            KeyCode::Char('+') => self.resize_window(SplitDir::Horizontal, 5),
+            KeyCode::Char('-') => self.resize_window(SplitDir::Horizontal, -5),
+            KeyCode::Char('>') => self.resize_window(SplitDir::Vertical, 5),
+            KeyCode::Char('<') => self.resize_window(SplitDir::Vertical, -5),
+            _ => {}
+        }
+    }
+
+    // Function to save the view of the active window, so another one can take the focus
+    fn store_view(&mut self) {
+        let window = &mut self.windows[self.active];
+        window.buffer = self.current;
+        window.cursor = self.cursor;
+        window.rowoff = self.rowoff;
+        window.coloff = self.coloff;
+    }
+
+    fn focus_window(&mut self, idx: usize) {
+        self.store_view();
+        self.load_view(idx);
+    }
+
+    // Function to give the focus to a window, taking over its view
+    fn load_view(&mut self, idx: usize) {
+        self.active = idx;
+
+        let window = &self.windows[idx];
+        self.current = window.buffer;
+        (self.cursor, self.rowoff, self.coloff) = (window.cursor, window.rowoff, window.coloff);
+        self.leave_visual();
+
+        // The buffer could have been changed through another window
+        self.cursor.y = self.cursor.y.min(self.rows().len() as u16);
+        let rowlen = self.rows().get(self.cursor.y as usize).map_or(0, |row| row.len());
+        self.cursor.x = self.cursor.x.min(rowlen as u16);
+    }
+
+    // Function to move the focus to the nearest window in a direction
+    fn focus_towards(&mut self, key: EditorKey) {
+        let (windows, _) = self.arrange();
+        let Some(&(_, from)) = windows.iter().find(|(idx, _)| *idx == self.active) else {
+            return;
+        };
+
+        // Windows on that side which overlap with this one, the closest first
+        let target = windows
+            .iter()
+            .filter(|(_, to)| match key {
+                EditorKey::Up => to.y + to.height <= from.y,
+                EditorKey::Down => to.y >= from.y + from.height,
+                EditorKey::Left => to.x + to.width <= from.x,
+                EditorKey::Right => to.x >= from.x + from.width,
+            })
+            .filter(|(_, to)| match key {
+                EditorKey::Up | EditorKey::Down => to.x < from.x + from.width && from.x < to.x + to.width,
+                EditorKey::Left | EditorKey::Right => to.y < from.y + from.height && from.y < to.y + to.height,
+            })
+            .min_by_key(|(_, to)| match key {
+                EditorKey::Up => from.y - to.y,
+                EditorKey::Down => to.y - from.y,
+                EditorKey::Left => from.x - to.x,
+                EditorKey::Right => to.x - from.x,
+            })
+            .map(|(idx, _)| *idx);
+
+        if let Some(idx) = target {
+            self.focus_window(idx);
+        }
+    }
+
+    // Function to show the active window twice, the new window gets the focus
+    fn split_window(&mut self, dir: SplitDir) {
+        let area = self.text_area();
+        // Both halves need a row of text and a status bar, or a column of text
+        let too_small = match dir {
+            SplitDir::Horizontal => area.height + 1 < 4,
+            SplitDir::Vertical => area.width < 3
+        };
+        if too_small {
+            self.set_status_msg(String::from("Window too small to split"));
+            return;
+        }
+
+        self.store_view();
+        let window = &self.windows[self.active];
+        let new = Window { buffer: window.buffer, cursor: window.cursor, rowoff: window.rowoff, coloff: window.coloff };
+        self.windows.push(new);
+        self.layout.split(self.active, self.windows.len() - 1, dir);
+        self.focus_window(self.windows.len() - 1);
+    }
+
+    fn close_window(&mut self) {
+        if self.windows.len() == 1 {
+            self.set_status_msg(String::from("Can't close the last window"));
+            return;
+        }
+
+        let closed = self.active;
+        self.windows.remove(closed);
+        self.layout.remove(closed);
+
+        // The view of the closed window is dropped, not stored
+        self.load_view(closed.min(self.windows.len() - 1));
+    }
+
+    fn resize_window(&mut self, dir: SplitDir, delta: i16) {
+        if !self.layout.resize(self.active, dir, delta) {
+            self.set_status_msg(String::from("No split to resize that way"));
+        }
+    }
+
+    // Function to refuse changes to a read-only file
+    fn is_read_only(&mut self) -> bool {
+        let read_only = self.buf().read_only;
+        if read_only {
+            self.set_status_msg(String::from("File is read-only"));
+        }
+        read_only
+    }
+
+    fn set_status_msg(&mut self, message: String) {
+        self.status_time = Instant::now();
+        self.status_msg = message;
+    }
+
+    // Function to delete character left of the cursor from the screen
+    fn editor_del_char(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        if self.cursor.y as usize == self.rows().len() {
+            return;
+        }
+
+        if self.cursor.x as usize == 0 && self.cursor.y == 0 {
+            return;
+        }
+
+        self.begin_step(false);
+
+        if self.cursor.x > 0 {
+            let row = &self.rows()[self.cursor.y as usize];
+            let start = row.byte_index(self.cursor.x as usize - 1);
+            let end = row.byte_index(self.cursor.x as usize);
+            let text = row.characters[start..end].to_string();
+            self.do_edit(Edit::Delete { at: TextPos { row: self.cursor.y as usize, byte: start }, text });
+            self.cursor.x -= 1;
+        } else {
+            let prev = self.cursor.y as usize - 1;
+            let at = TextPos { row: prev, byte: self.rows()[prev].characters.len() };
+            self.cursor.x = self.rows()[prev].len() as u16;
+            if let Some(row) = self.del_row(self.cursor.y as usize) {
+                self.do_edit(Edit::Insert { at, text: row });
+                self.cursor.y -= 1;
+            }
+        }
+        
+        self.commit_step();
+    }
+    
+    // Function to delete a row and return its contents
+    fn del_row(&mut self, at: usize) -> Option<String>{
+        if at >= self.rows().len() {
+            None
+        } else {
+            let text = self.rows()[at].characters.clone();
+            self.do_edit(Edit::DeleteRow { at, text: text.clone() });
+            Some(text)
+        }
+
+    }
+
+    fn insert_new_line(&mut self){
+        if self.is_read_only() {
+            return;
+        }
+
+        self.begin_step(false);
+        self.delete_selection();
+
+        if self.cursor.x == 0 {
             self.insert_row(self.cursor.y as usize, "".to_string());
         } else {
-            let new_row = self.rows[self.cursor.y as usize].rowsplit(self.cursor.x as usize);
+            let row = &self.rows()[self.cursor.y as usize];
+            let at = TextPos { row: self.cursor.y as usize, byte: row.byte_index(self.cursor.x as usize) };
+            let new_row = row.characters[at.byte..].to_string();
+            self.do_edit(Edit::Delete { at, text: new_row.clone() });
             self.insert_row(self.cursor.y as usize + 1, new_row);
         }
         self.cursor.y += 1;
         self.cursor.x = 0;
+
+        self.commit_step();
     }
 
     // Prompts the user if saves without filename
@@ -464,9 +1627,10 @@ impl Editor {
         pmsg: &str, 
         callback: Option<fn(&mut Editor, &str, PromptKey)>) -> Option<String> {
         let mut buf = String::from("");
+        self.prompt_info.clear();
 
         loop {
-            self.set_status_msg(format!("{}: {}", pmsg, buf));
+            self.set_status_msg(format!("{}: {}{}", pmsg, buf, self.prompt_info));
             let _ = self.refresh_screen();
             if let Ok(c) = self.keyboard.read_key() {
                 let mut prompt_key: Option<PromptKey> = None;
@@ -479,6 +1643,7 @@ impl Editor {
                             callback(self, &buf, PromptKey::Enter);
                         }
                         self.set_status_msg("".to_string());
+                        self.prompt_info.clear();
                         return Some(buf);
                     },
 
@@ -490,6 +1655,7 @@ impl Editor {
                             callback(self, &buf, PromptKey::Escape);
                         }
                         self.set_status_msg("".to_string());
+                        self.prompt_info.clear();
                         return None;
                     },
 
@@ -497,7 +1663,7 @@ impl Editor {
                         code: KeyCode::Char(ch),
                         modifiers: KeyModifiers::NONE | KeyModifiers::SHIFT
                     } => {
-                        prompt_key = Some(PromptKey::Char(ch));
+                        prompt_key = Some(PromptKey::Char);
                         buf.push(ch);
                     },
 
@@ -510,9 +1676,24 @@ impl Editor {
                         code: KeyCode::Char('h'),
                         modifiers: KeyModifiers::CONTROL
                     } => {
+                        prompt_key = Some(PromptKey::Char);
                         buf.pop();
                     },
 
+                    KeyEvent {
+                        code: KeyCode::Char(ch),
+                        modifiers: KeyModifiers::CONTROL
+                    } => {
+                        prompt_key = Some(PromptKey::Ctrl(ch));
+                    },
+
+                    KeyEvent {
+                        code: KeyCode::Tab,
+                        ..
+                    } => {
+                        prompt_key = Some(PromptKey::Tab);
+                    },
+
                     KeyEvent {
                         code: KeyCode::Down,
                         ..
@@ -548,7 +1729,19 @@ impl Editor {
                     if let Some(key) = prompt_key {
                         callback(self, &buf, key);
                     }
+                    if let Some(text) = self.prompt_text.take() {
+                        buf = text;
+                    }
+                }
+            }
+            // Input which ended cancels the prompt
+            else {
+                if let Some(callback) = callback {
+                    callback(self, &buf, PromptKey::Escape);
                 }
+                self.set_status_msg("".to_string());
+                self.prompt_info.clear();
+                return None;
             }
         }
     }
@@ -558,7 +1751,7 @@ impl Editor {
         // Saving cursor position and scroll position
         let (saved_position, saved_coloff, saved_rowoff) = (self.cursor, self.coloff, self.rowoff);
 
-        if self.prompt("Search (Use Arrow/Enter/Esc)", Some(Editor::find_callback)).is_none() {
+        if self.prompt("Search (^R regex, ^C case, ^W word)", Some(Editor::find_callback)).is_none() {
             self.cursor = saved_position;
             self.coloff = saved_coloff;
             self.rowoff = saved_rowoff;
@@ -567,57 +1760,819 @@ impl Editor {
     
     // To search a particular character or string in file
     fn find_callback(&mut self, query: &str, event: PromptKey) {
-        // To enable forward and backward search
+        let count = self.matches.len();
         match event {
+            // The matches are only shown while the prompt is open
             PromptKey::Enter | PromptKey::Escape => {
-                self.last_match = None;
-                self.direction = SearchDirection::Forward;
+                self.matches.clear();
+                self.current_match = None;
+                return;
             }
 
-            PromptKey::Next => self.direction = SearchDirection::Forward,
-            PromptKey::Prev => self.direction = SearchDirection::Backward,
-            _=> {
-                self.last_match = None;
-                self.direction = SearchDirection::Forward;
+            // To enable forward and backward search
+            PromptKey::Next | PromptKey::Prev => {
+                let Some(current) = self.current_match else {
+                    return;
+                };
+                self.current_match = Some(match event {
+                    PromptKey::Next => (current + 1) % count,
+                    _ => (current + count - 1) % count
+                });
             }
+
+            // Toggling the search options starts the search over
+            PromptKey::Ctrl(ch) => {
+                if !self.toggle_search_option(ch) {
+                    return;
+                }
+                self.update_matches(query);
+            }
+
+            PromptKey::Char => self.update_matches(query),
+            PromptKey::Tab => return
         }
 
-        let mut current = if let Some(line) = self.last_match {
-            line
+        if let Some(current) = self.current_match {
+            let found = self.matches[current];
+            self.cursor.y = found.row as u16;
+            self.cursor.x = self.rows()[found.row].grapheme_index(found.start) as u16;
+            self.rowoff = self.rows().len() as u16;
+            self.prompt_info = format!("{} {}/{} matches", self.search.label(), current + 1, self.matches.len());
+        }
+    }
+
+    // Function to look for every match of the query in the buffer, the first one becomes current
+    fn update_matches(&mut self, query: &str) {
+        self.matches.clear();
+        self.current_match = None;
+        self.prompt_info = self.search.label();
+        if query.is_empty() {
+            return;
+        }
+
+        let pattern = match self.search.compile(query) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                self.prompt_info = format!("{} (invalid pattern: {})", self.prompt_info, err);
+                return;
+            }
+        };
+        self.matches = find_all(&pattern, self.rows().iter().map(|row| row.characters.as_str()));
+        if self.matches.is_empty() {
+            self.prompt_info = format!("{} (no matches)", self.prompt_info);
         } else {
-            self.direction = SearchDirection::Forward;
-            self.rows.len()
+            self.current_match = Some(0);
+        }
+    }
+
+    // Function to flip one of the search options from the Ctrl key pressed in the prompt
+    // Returns false if the key isn't one of them
+    fn toggle_search_option(&mut self, ch: char) -> bool {
+        match ch {
+            'r' => self.search.regex = !self.search.regex,
+            'c' => self.search.ignore_case = !self.search.ignore_case,
+            'w' => self.search.whole_word = !self.search.whole_word,
+            _ => return false
+        }
+        true
+    }
+
+    // Function to replace the matches of a query one by one, asking about each of them
+    // Goes from the cursor to the end of the file and then wraps around to where it started
+    fn replace(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let query = match self.prompt("Replace (^R regex, ^C case, ^W word)", Some(Editor::replace_callback)) {
+            Some(query) if !query.is_empty() => query,
+            _ => return
+        };
+        let pattern = match self.search.compile(&query) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                self.set_status_msg(format!("Invalid pattern: {}", err));
+                return;
+            }
+        };
+        let Some(replacement) = self.prompt(&format!("Replace {} with", query), None) else {
+            return;
+        };
+
+        let mut pos = if (self.cursor.y as usize) < self.rows().len() {
+            TextPos {
+                row: self.cursor.y as usize,
+                byte: self.rows()[self.cursor.y as usize].byte_index(self.cursor.x as usize)
+            }
+        } else {
+            TextPos { row: self.rows().len(), byte: 0 }
+        };
+        let mut stop = pos;
+        let mut wrapped = false;
+        let mut replace_all = false;
+        let mut count = 0;
+
+        // All the replacements are undone together
+        self.begin_step(false);
+        loop {
+            if pos.row >= self.rows().len() || (wrapped && pos.row > stop.row) {
+                if wrapped {
+                    break;
+                }
+                wrapped = true;
+                pos = TextPos { row: 0, byte: 0 };
+                continue;
+            }
+
+            // The matched text and what it gets replaced with, capture groups
+            // like $1 are only filled in when searching for a regex
+            let line = &self.rows()[pos.row].characters;
+            let Some((start, matched, text)) = next_match(&pattern, line, pos.byte).map(|caps| {
+                let found = caps.get(0).unwrap();
+                let mut text = String::new();
+                if self.search.regex {
+                    caps.expand(&replacement, &mut text);
+                } else {
+                    text.push_str(&replacement);
+                }
+                (found.start(), found.as_str().to_string(), text)
+            }) else {
+                pos = TextPos { row: pos.row + 1, byte: 0 };
+                continue;
+            };
+            if wrapped && pos.row == stop.row && start >= stop.byte {
+                break;
+            }
+
+            let at = TextPos { row: pos.row, byte: start };
+            let end = start + matched.len();
+            self.cursor = CursorPos {
+                x : self.rows()[at.row].grapheme_index(start) as u16,
+                y : at.row as u16
+            };
+
+            if !replace_all {
+                self.matches = vec![Match { row: at.row, start, end }];
+                self.current_match = Some(0);
+                let answer = self.ask_replace();
+                self.matches.clear();
+                self.current_match = None;
+
+                match answer {
+                    'y' => {},
+                    'a' => replace_all = true,
+                    'n' => {
+                        pos.byte = end;
+                        continue;
+                    },
+                    _ => break
+                }
+            }
+
+            self.do_edit(Edit::Delete { at, text: matched });
+            if !text.is_empty() {
+                self.do_edit(Edit::Insert { at, text: text.clone() });
+            }
+            count += 1;
+
+            // Carrying on after the inserted text, which may have split the row
+            let after = at.after(&text);
+            // Past the wrap around the place to stop at can move too
+            if wrapped {
+                if stop.row == at.row {
+                    stop = TextPos { row: after.row, byte: stop.byte.saturating_sub(end) + after.byte };
+                } else {
+                    stop.row += after.row - at.row;
+                }
+            }
+            pos = after;
+        }
+        self.commit_step();
+
+        let plural = if count == 1 { "" } else { "s" };
+        self.set_status_msg(format!("Replaced {} occurrence{}", count, plural));
+    }
+
+    // Lets the search options be toggled while typing what to replace
+    fn replace_callback(&mut self, query: &str, event: PromptKey) {
+        if let PromptKey::Ctrl(ch) = event {
+            self.toggle_search_option(ch);
+        }
+
+        self.prompt_info = self.search.label();
+        if query.is_empty() {
+            return;
+        }
+        if let Err(err) = self.search.compile(query) {
+            self.prompt_info = format!("{} (invalid pattern: {})", self.prompt_info, err);
+        }
+    }
+
+    // Function to ask what to do with the match under the cursor
+    // Returns 'y', 'n', 'a' or 'q'
+    fn ask_replace(&mut self) -> char {
+        self.set_status_msg(String::from("Replace this one? (y)es | (n)o | (a)ll | (q)uit"));
+        loop {
+            let _ = self.refresh_screen();
+            match self.keyboard.read_key() {
+                Ok(KeyEvent { code : KeyCode::Char(ch @ ('y' | 'n' | 'a' | 'q')), .. }) => return ch,
+                Ok(KeyEvent { code : KeyCode::Esc, .. }) | Err(_) => return 'q',
+                _ => {}
+            }
+        }
+    }
+
+    // Function to handle a key in normal or visual mode
+    fn vi_key(&mut self, key: KeyEvent) -> bool {
+        let Some(ch) = vi_char(&key) else {
+            let idle = self.vi.pending.is_empty();
+            self.vi.pending.clear();
+            self.vi.keys.clear();
+            if key.code == KeyCode::Esc {
+                self.vi.mode = Mode::Normal;
+                return false;
+            }
+            // Keys vi has no use for, like Ctrl-s, do what they are bound to,
+            // unless that would edit the text, like Tab or Ctrl-h
+            let edits = self.config.keymap.lookup(&key).is_some_and(|command| command.edits_text());
+            let quit = idle && !edits && self.run_key(key);
+            self.vi_clamp();
+            return quit;
         };
 
-        for _ in 0..self.rows.len() {
-            match self.direction {
-                SearchDirection::Forward => {
-                    current += 1;
-                    if current >= self.rows.len() {
-                        current = 0;
+        self.vi.pending.push(ch);
+        self.vi.keys.push(key);
+        // q on its own ends the recording of a macro
+        if self.vi.pending == "q" && self.macros.recording.is_some() {
+            self.vi.pending.clear();
+            self.vi.keys.clear();
+            self.stop_macro();
+            return false;
+        }
+        let command = match parse(&self.vi.pending, self.vi.mode == Mode::Visual) {
+            Parse::Pending => return false,
+            Parse::Invalid => None,
+            Parse::Done(command) => Some(command)
+        };
+        self.vi.pending.clear();
+        let keys = std::mem::take(&mut self.vi.keys);
+        let Some(command) = command else {
+            return false;
+        };
+
+        // The keys of a change are kept for '.', along with what gets typed
+        // if it starts inserting
+        if command.action.is_change() && self.vi.mode == Mode::Normal {
+            self.vi.recording = Some(keys);
+            self.vi.insert_from = Some((self.current, self.buf().history.steps()));
+        }
+        let quit = self.vi_run(command);
+        if self.vi.mode != Mode::Insert {
+            if let Some(keys) = self.vi.recording.take() {
+                self.vi.last_change = keys;
+            }
+            self.vi.insert_from = None;
+            self.vi_clamp();
+        }
+        quit
+    }
+
+    fn vi_run(&mut self, command: ViCommand) -> bool {
+        let times = command.count.unwrap_or(1);
+        match command.action {
+            Action::Move(motion) => self.cursor = apply_motion(self.rows(), self.cursor, motion, command.count),
+            Action::Operate(operator, motion) => self.vi_operate(operator, motion, command.count),
+            Action::Insert(at) => self.vi_insert(at),
+            Action::Put { before } => self.vi_put(before, times),
+            // A count bigger than the history stops when it runs out
+            Action::Undo => for _ in 0..times {
+                if !self.undo() {
+                    break;
+                }
+            },
+            Action::Redo => for _ in 0..times {
+                if !self.redo() {
+                    break;
+                }
+            },
+            Action::Repeat => {
+                let keys = self.vi.last_change.clone();
+                if keys.len().saturating_mul(times) > MAX_PLAYBACK {
+                    self.set_status_msg(format!("More than {} keys to repeat", MAX_PLAYBACK));
+                    return false;
+                }
+                for _ in 0..times {
+                    for key in &keys {
+                        if self.handle_key(*key) {
+                            return true;
+                        }
                     }
-                },
-                
-                SearchDirection::Backward => {
-                    if current == 0 {
-                        current = self.rows.len() - 1;
-                    } else {
-                        current -= 1;
+                }
+            },
+            Action::Visual => {
+                if self.vi.mode == Mode::Visual {
+                    self.vi.mode = Mode::Normal;
+                } else {
+                    self.vi.mode = Mode::Visual;
+                    self.vi.anchor = self.cursor;
+                }
+            },
+            Action::CommandLine => return self.command_line(),
+            Action::Record(register) => self.start_macro(register),
+            Action::Play(register) => self.play(register, times)
+        }
+        false
+    }
+
+    // Esc in insert mode, the cursor goes back onto the last character typed
+    fn leave_insert(&mut self) {
+        self.vi.mode = Mode::Normal;
+        self.cursor.x = self.cursor.x.saturating_sub(1);
+        if let Some(keys) = self.vi.recording.take() {
+            self.vi.last_change = keys;
+        }
+        // Undone in one go like in vi, unless another buffer got shown meanwhile
+        if let Some((buffer, from)) = self.vi.insert_from.take() {
+            if buffer == self.current {
+                self.buf_mut().history.join_since(from);
+            }
+        }
+        self.vi_clamp();
+    }
+
+    // The selection belongs to the view it was made in
+    fn leave_visual(&mut self) {
+        if self.vi.mode == Mode::Visual {
+            self.vi.mode = Mode::Normal;
+        }
+    }
+
+    // In normal mode the cursor stays on a character of a row
+    fn vi_clamp(&mut self) {
+        self.cursor.y = self.cursor.y.min(self.rows().len().saturating_sub(1) as u16);
+        let row_len = self.rows().get(self.cursor.y as usize).map_or(0, |row| row.len());
+        self.cursor.x = self.cursor.x.min(row_len.saturating_sub(1) as u16);
+    }
+
+    // Function to convert a cursor position into a position for an edit
+    // Past the last row is the end of the text
+    fn text_pos(&self, pos: CursorPos) -> TextPos {
+        let row = pos.y as usize;
+        match self.rows().get(row) {
+            Some(text) => TextPos { row, byte: text.byte_index(pos.x as usize) },
+            None => {
+                let last = self.rows().len().saturating_sub(1);
+                TextPos { row: last, byte: self.rows().get(last).map_or(0, |row| row.characters.len()) }
+            }
+        }
+    }
+
+    // The position just after the grapheme at pos, on the same row
+    fn grapheme_after(&self, pos: CursorPos) -> CursorPos {
+        let row_len = self.rows().get(pos.y as usize).map_or(0, |row| row.len());
+        CursorPos { x: (pos.x + 1).min(row_len as u16), ..pos }
+    }
+
+    // Text from one position to another, the rows joined with '\n'
+    fn text_between(&self, from: TextPos, to: TextPos) -> String {
+        let rows = self.rows();
+        if from.row == to.row {
+            return rows[from.row].characters[from.byte..to.byte].to_string();
+        }
+
+        let mut text = rows[from.row].characters[from.byte..].to_string();
+        for row in &rows[from.row + 1..to.row] {
+            text.push('\n');
+            text.push_str(&row.characters);
+        }
+        text.push('\n');
+        text.push_str(&rows[to.row].characters[..to.byte]);
+        text
+    }
+
+    // The selection as positions for draw_tildes(), the end excluded
+    // In visual mode the character under the cursor is selected too
+    fn selection(&self) -> Option<(TextPos, TextPos)> {
+        if self.rows().is_empty() {
+            return None;
+        }
+        let (from, to) = if self.vi.mode == Mode::Visual {
+            let (start, end) = ordered(self.vi.anchor, self.cursor);
+            (self.text_pos(start), self.text_pos(self.grapheme_after(end)))
+        } else {
+            let (start, end) = ordered(self.mark?, self.cursor);
+            (self.text_pos(start), self.text_pos(end))
+        };
+        Some((from, to)).filter(|(from, to)| from != to)
+    }
+
+    // Function to delete, yank or change the text from the cursor to where the
+    // motion lands, whole lines without a motion, or the selection in visual mode
+    fn vi_operate(&mut self, operator: Operator, motion: Option<Motion>, count: Option<usize>) {
+        let visual = self.vi.mode == Mode::Visual;
+        self.vi.mode = Mode::Normal;
+        if self.rows().is_empty() || (operator != Operator::Yank && self.is_read_only()) {
+            return;
+        }
+
+        let cursor = self.cursor;
+        let last_row = self.rows().len() - 1;
+        let span = match motion {
+            _ if visual => {
+                let (start, end) = ordered(self.vi.anchor, cursor);
+                Span::Chars(start, self.grapheme_after(end))
+            },
+            None => {
+                let first = cursor.y as usize;
+                Span::Lines(first, first.saturating_add(count.unwrap_or(1) - 1).min(last_row))
+            },
+            Some(motion) if motion.linewise() => {
+                let target = apply_motion(self.rows(), cursor, motion, count);
+                let (start, end) = ordered(cursor, target);
+                Span::Lines(start.y as usize, end.y as usize)
+            },
+            Some(mut motion) => {
+                // cw changes to the end of the word like ce
+                if operator == Operator::Change && motion == Motion::WordStart && !on_blank(self.rows(), cursor) {
+                    motion = Motion::WordEnd;
+                }
+                let mut target = apply_motion(self.rows(), cursor, motion, count);
+                // dw on the last word of a line stops at its end
+                if motion == Motion::WordStart && target.y > cursor.y {
+                    target = CursorPos { x: self.rows()[cursor.y as usize].len() as u16, y: cursor.y };
+                }
+                let (start, end) = ordered(cursor, target);
+                let end = if motion.inclusive() { self.grapheme_after(end) } else { end };
+                Span::Chars(start, end)
+            }
+        };
+
+        match span {
+            Span::Chars(start, end) => {
+                let (from, to) = (self.text_pos(start), self.text_pos(end));
+                let text = self.text_between(from, to);
+                self.store(Register { text: text.clone(), linewise: false });
+
+                if operator != Operator::Yank && !text.is_empty() {
+                    self.begin_step(false);
+                    self.do_edit(Edit::Delete { at: from, text });
+                    self.commit_step();
+                }
+                self.cursor = start;
+            },
+
+            Span::Lines(first, last) => {
+                let text = self.rows()[first..=last]
+                    .iter()
+                    .map(|row| row.characters.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n");
+                self.store(Register { text, linewise: true });
+
+                if operator != Operator::Yank {
+                    self.begin_step(false);
+                    // A change keeps one empty line to type into
+                    let keep = usize::from(operator == Operator::Change);
+                    for _ in first + keep..=last {
+                        self.del_row(first + keep);
+                    }
+                    if keep == 1 && !self.rows()[first].characters.is_empty() {
+                        let text = self.rows()[first].characters.clone();
+                        self.do_edit(Edit::Delete { at: TextPos { row: first, byte: 0 }, text });
                     }
+                    self.commit_step();
                 }
+                self.cursor = CursorPos { x: 0, y: first as u16 };
             }
+        }
 
-            if let Some(m) = self.rows[current].characters
-                .match_indices(query)
-                .take(1)
-                .next() 
-            {   
-                self.last_match = Some(current);
-                self.cursor.y = current as u16;
-                self.cursor.x = m.0 as u16;
-                self.rowoff = self.rows.len() as u16;
-                break;
+        if operator == Operator::Change {
+            self.vi.mode = Mode::Insert;
+        }
+    }
+
+    // Function to start inserting for i, a, I, A, o and O
+    fn vi_insert(&mut self, at: InsertAt) {
+        if self.is_read_only() {
+            return;
+        }
+
+        let y = self.cursor.y as usize;
+        let row_len = self.rows().get(y).map_or(0, |row| row.len()) as u16;
+        match at {
+            InsertAt::Before => {},
+            InsertAt::After => self.cursor.x = (self.cursor.x + 1).min(row_len),
+            InsertAt::LineStart => self.cursor.x = self.rows().get(y).map_or(0, first_non_blank) as u16,
+            InsertAt::LineEnd => self.cursor.x = row_len,
+            InsertAt::LineBelow | InsertAt::LineAbove => {
+                let at = if at == InsertAt::LineBelow { y + 1 } else { y }.min(self.rows().len());
+                self.insert_row(at, String::new());
+                self.cursor = CursorPos { x: 0, y: at as u16 };
+            }
+        }
+        self.vi.mode = Mode::Insert;
+    }
+
+    // Function to put the register after the cursor, or before it for P
+    fn vi_put(&mut self, before: bool, times: usize) {
+        if self.is_read_only() {
+            return;
+        }
+        let Some(register) = self.clipboard.load(&self.config) else {
+            self.set_status_msg(String::from("Nothing to put"));
+            return;
+        };
+        let (text, linewise) = (register.text.clone(), register.linewise);
+        if (text.len() + 1).saturating_mul(times) > MAX_PUT {
+            self.set_status_msg(format!("Can't put more than {} bytes", MAX_PUT));
+            return;
+        }
+
+        self.begin_step(false);
+        if linewise {
+            let y = self.cursor.y as usize;
+            let at = if before { y } else { y + 1 }.min(self.rows().len());
+            let lines = vec![text.as_str(); times].join("\n");
+            for (idx, line) in lines.split('\n').enumerate() {
+                self.insert_row(at + idx, line.to_string());
+            }
+            self.cursor = CursorPos { x: 0, y: at as u16 };
+        } else {
+            if self.rows().is_empty() {
+                self.insert_row(0, String::new());
+            }
+            let y = self.cursor.y as usize;
+            let x = if before || self.rows()[y].len() == 0 { self.cursor.x } else { self.cursor.x + 1 };
+            let at = self.text_pos(CursorPos { x, y: y as u16 });
+            let text = text.repeat(times);
+            self.do_edit(Edit::Insert { at, text: text.clone() });
+
+            // The cursor ends on the last character put
+            let end = at.after(&text);
+            self.cursor = CursorPos {
+                x : self.rows()[end.row].grapheme_index(end.byte).saturating_sub(1) as u16,
+                y : end.row as u16
+            };
+        }
+        self.commit_step();
+    }
+
+    // Function to read a command like "w file", "set tabstop=4" or "%s/a/b/g" and run it,
+    // returns true to quit
+    fn command_line(&mut self) -> bool {
+        self.commands.reset();
+        let Some(line) = self.prompt("", Some(Editor::command_callback)) else {
+            return false;
+        };
+        self.commands.push(&line);
+        self.ex_command(&line)
+    }
+
+    // Tab completes the command or the file, Up and Down go through the
+    // commands run before
+    fn command_callback(&mut self, line: &str, event: PromptKey) {
+        self.prompt_info.clear();
+        match event {
+            PromptKey::Tab => {
+                let completion = complete(line);
+                self.prompt_text = Some(completion.line);
+                if !completion.choices.is_empty() {
+                    self.prompt_info = format!("  ({})", completion.choices.join(" "));
+                }
+            },
+            PromptKey::Prev => self.prompt_text = self.commands.prev(line).map(String::from),
+            PromptKey::Next => self.prompt_text = self.commands.next().map(String::from),
+            _ => {}
+        }
+    }
+
+    fn ex_command(&mut self, line: &str) -> bool {
+        let command = match ExCommand::parse(line) {
+            Ok(command) => command,
+            Err(err) => {
+                self.set_status_msg(err);
+                return false;
+            }
+        };
+        let arg = command.arg.trim_end();
+        if command.range.is_some() && !matches!(command.name, "" | "s" | "substitute") {
+            self.set_status_msg(format!("No range allowed: {}", line.trim()));
+            return false;
+        }
+
+        match (command.name, command.bang) {
+            // A line on its own goes there, like 120 or $
+            ("", false) => if let Some(range) = command.range {
+                match self.range_rows(range) {
+                    Ok((_, last)) => self.jump_to(CursorPos { x: 0, y: last as u16 }),
+                    Err(err) => self.set_status_msg(err)
+                }
+            },
+            ("w" | "write", bang) => {
+                self.write(arg, bang);
+            },
+            ("q" | "quit", false) => return self.quit_checked(),
+            ("q" | "quit", true) => return true,
+            ("wq" | "x", bang) => return self.write(arg, bang) && (bang || self.quit_checked()),
+            // Every file gets a buffer of its own, so there are no changes for ! to throw away
+            ("e" | "edit", true) => self.set_status_msg(String::from("No ! allowed, the changes stay in their buffer")),
+            ("e" | "edit", false) if arg.is_empty() => self.set_status_msg(String::from("Argument required")),
+            ("e" | "edit", false) => self.open_buffer(arg),
+            ("set", false) if arg.is_empty() => self.set_status_msg(String::from("Argument required")),
+            ("set", false) => for setting in arg.split_whitespace() {
+                if let Err(err) = self.set_option(setting) {
+                    self.set_status_msg(err);
+                    break;
+                }
+            },
+            ("goto", false) => match Target::parse(arg).and_then(|target| self.target_pos(target)) {
+                Ok(pos) => self.jump_to(pos),
+                Err(err) => self.set_status_msg(err)
+            },
+            ("s" | "substitute", false) => match Substitute::parse(command.arg) {
+                Ok(substitute) => {
+                    let here = Address { base: Base::Current, offset: 0 };
+                    match self.range_rows(command.range.unwrap_or(Range::Lines(here, here))) {
+                        Ok(rows) => self.substitute(rows, &substitute),
+                        Err(err) => self.set_status_msg(err)
+                    }
+                },
+                Err(err) => self.set_status_msg(err)
+            },
+            _ => self.set_status_msg(format!("Not an editor command: {}", line.trim()))
+        }
+        false
+    }
+
+    // Function for :w, with a file the buffer is written there and keeps its name,
+    // like vi does, ! lets it replace a file which is already there
+    // Returns true when the text got written
+    fn write(&mut self, filename: &str, overwrite: bool) -> bool {
+        if filename.is_empty() || self.buf().filename.is_empty() || filename == self.buf().filename {
+            if !filename.is_empty() {
+                self.buf_mut().set_filename(filename.to_string());
+            }
+            self.save();
+            return self.buf().dirty == 0;
+        }
+        if !overwrite && std::path::Path::new(filename).exists() {
+            self.set_status_msg(String::from("File exists (add ! to override)"));
+            return false;
+        }
+        if !self.confirm_endings() {
+            self.set_status_msg(String::from("Save aborted"));
+            return false;
+        }
+
+        match self.buf().write_to(filename) {
+            Ok(len) => {
+                self.set_status_msg(format!("{} bytes written to {}", len, filename));
+                true
+            },
+            Err(err) => {
+                self.set_status_msg(format!("Can't save! {}", err));
+                false
+            }
+        }
+    }
+
+    // Function to turn a range into the first and last row it takes, counted from 0
+    fn range_rows(&self, range: Range) -> EditorResult<(usize, usize), String> {
+        let last = self.rows().len().max(1);
+        let (first, second) = match range {
+            Range::Whole => return Ok((0, last - 1)),
+            Range::Lines(first, second) => (first, second)
+        };
+
+        let row = |address: Address| {
+            let base = match address.base {
+                Base::Line(line) => line,
+                Base::Current => self.cursor.y as usize + 1,
+                Base::Last => last
+            };
+            let line = (base as isize).saturating_add(address.offset);
+            if line < 1 || line > last as isize {
+                return Err(format!("Line {} is out of range, the lines are 1 to {}", line, last));
+            }
+            Ok(line as usize - 1)
+        };
+        let (first, second) = (row(first)?, row(second)?);
+        Ok((first.min(second), first.max(second)))
+    }
+
+    // Function to change a setting the way the config does, for "set"
+    fn set_option(&mut self, setting: &str) -> EditorResult<(), String> {
+        let (key, value) = parse_setting(setting);
+        self.config.set(key, &value)?;
+
+        match key {
+            "tab_width" => {
+                set_tab_stop(self.config.tab_width);
+                for buffer in &mut self.buffers {
+                    buffer.render_again();
+                }
+            },
+            "soft_wrap" => self.set_soft_wrap(self.config.soft_wrap),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set_soft_wrap(&mut self, on: bool) {
+        self.config.soft_wrap = on;
+        // coloff means something else with soft wrap, also for the other
+        // windows and the buffers out of sight
+        self.coloff = 0;
+        for window in &mut self.windows {
+            window.coloff = 0;
+        }
+        for buffer in &mut self.buffers {
+            buffer.coloff = 0;
+        }
+    }
+
+    // Function to replace what a pattern matches in the rows [first, last],
+    // in each row the first match or with the g flag every one
+    fn substitute(&mut self, (first, last): (usize, usize), substitute: &Substitute) {
+        if self.is_read_only() {
+            return;
+        }
+        let options = SearchOptions { regex: true, ignore_case: substitute.ignore_case, whole_word: false };
+        let pattern = match options.compile(&substitute.pattern) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                self.set_status_msg(format!("Invalid pattern: {}", err));
+                return;
+            }
+        };
+
+        let (mut count, mut lines) = (0, 0);
+        self.begin_step(false);
+        for row in first..(last + 1).min(self.rows().len()) {
+            // Empty matches count here, so ^ and $ can add to the start or end of a line
+            let line = &self.rows()[row].characters;
+            let found = pattern
+                .captures_iter(line)
+                .take(if substitute.global { usize::MAX } else { 1 })
+                .map(|caps| {
+                    let matched = caps.get(0).unwrap();
+                    let mut text = String::new();
+                    caps.expand(&substitute.replacement, &mut text);
+                    (matched.start(), matched.as_str().to_string(), text)
+                })
+                .collect::<Vec<_>>();
+            if found.is_empty() {
+                continue;
             }
+
+            self.cursor = CursorPos { x: 0, y: row as u16 };
+            count += found.len();
+            lines += 1;
+            // From the end of the row, so the matches before it keep their place
+            for (start, matched, text) in found.into_iter().rev() {
+                let at = TextPos { row, byte: start };
+                if !matched.is_empty() {
+                    self.do_edit(Edit::Delete { at, text: matched });
+                }
+                if !text.is_empty() {
+                    self.do_edit(Edit::Insert { at, text });
+                }
+            }
+        }
+        self.commit_step();
+
+        if count == 0 {
+            self.set_status_msg(format!("Pattern not found: {}", substitute.pattern));
+            return;
+        }
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        self.set_status_msg(format!("Replaced {} occurrence{} on {} line{}", count, plural(count), lines, plural(lines)));
+    }
+
+    // :q quits only if nothing is left unsaved, unlike Ctrl-q it doesn't ask again
+    fn quit_checked(&mut self) -> bool {
+        if self.buffers.iter().any(|buffer| buffer.dirty > 0) {
+            self.set_status_msg(String::from("No write since last change (add ! to override)"));
+            return false;
+        }
+        true
+    }
+
+    // Function to show a file, in the buffer it already has or in a new one
+    fn open_buffer(&mut self, filename: &str) {
+        if let Some(idx) = self.buffers.iter().position(|buffer| buffer.filename == filename) {
+            self.switch_buffer(idx);
+            return;
+        }
+
+        let (buffer, msg) = Buffer::open(filename);
+        self.buffers.push(buffer);
+        self.switch_buffer(self.buffers.len() - 1);
+        if let Some(msg) = msg {
+            self.set_status_msg(format!("{}: {}", filename, msg));
         }
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,410 @@
+use std::path::PathBuf;
+
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
+
+use crate::backend::{Headless, Script};
+use crate::buffer::Buffer;
+use crate::config::Config;
+use crate::keymap::{keys_to_script, parse_script, Command};
+use crate::macros::Macros;
+use super::Editor;
+
+const WIDTH: u16 = 50;
+const HEIGHT: u16 = 8;
+
+// Function to turn a script like "abc<enter><ctrl-s>" into key events
+fn keys(script: &str) -> Vec<Event> {
+    parse_script(script).unwrap().into_iter().map(Event::Key).collect()
+}
+
+// A file in the temp directory holding the text, named after the test using it
+fn temp_file(name: &str, text: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("kilo-test-{}-{}", std::process::id(), name));
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+// Function to run the editor over the keys of a script with the buffer,
+// stops when the script runs out
+fn run_buffer(config: Config, buffer: Buffer, script: &str) -> Editor {
+    let mut editor = Editor::build(
+        vec![buffer],
+        config,
+        Box::new(Script::new(keys(script))),
+        Box::new(Headless::new(WIDTH, HEIGHT))
+    ).unwrap();
+    editor.run().unwrap();
+    editor
+}
+
+// Function to run the editor on a file holding the text, the status bar
+// shows the name of the test and not where the file is
+fn run(name: &str, text: &str, script: &str) -> Editor {
+    run_with(Config::default(), name, text, script)
+}
+
+fn run_with(config: Config, name: &str, text: &str, script: &str) -> Editor {
+    let path = temp_file(name, text);
+    let (mut buffer, _) = Buffer::open(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    buffer.set_filename(name.to_string());
+    run_buffer(config, buffer, script)
+}
+
+// Function to compare the last frame with the expected one, the first
+// line of the expected text is left empty to keep the screen lined up
+fn assert_screen(editor: &Editor, expected: &str) {
+    let screen = editor.screen.lines().join("\n");
+    assert_eq!(screen, expected.strip_prefix('\n').unwrap_or(expected));
+}
+
+fn numbered(lines: usize) -> String {
+    (1..=lines).map(|n| format!("line {}\n", n)).collect()
+}
+
+#[test]
+fn insertion() {
+    let editor = run("insert.txt", "world\n", "hello<enter>there <end>!");
+    assert_screen(&editor, r"
+hello
+there world!
+~
+~
+~
+~
+insert.txt           (modified) - 2 lines | UTF-8
+Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl");
+    assert_eq!(editor.screen.cursor(), (12, 1));
+}
+
+#[test]
+fn deletion() {
+    let editor = run("delete.txt", "one\ntwo\nthree\n", "<down><backspace><delete><end><delete>");
+    assert_screen(&editor, r"
+onewothree
+~
+~
+~
+~
+~
+delete.txt           (modified) - 1 lines | UTF-8
+Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl");
+    assert_eq!(editor.screen.cursor(), (5, 0));
+}
+
+#[test]
+fn search() {
+    let editor = run("search.txt", "alpha\nbeta\ngamma beta\n", "<ctrl-f>gamma<enter>");
+    assert_screen(&editor, r"
+alpha
+beta
+gamma beta
+~
+~
+~
+search.txt            - 3 lines | UTF-8 | LF
+");
+    assert_eq!(editor.screen.cursor(), (0, 2));
+}
+
+#[test]
+fn search_cancelled() {
+    let editor = run("cancel.txt", "alpha\nbeta\ngamma beta\n", "<down><ctrl-f>gamma<esc>");
+    assert_screen(&editor, r"
+alpha
+beta
+gamma beta
+~
+~
+~
+cancel.txt            - 3 lines | UTF-8 | LF
+");
+    assert_eq!(editor.screen.cursor(), (0, 1));
+}
+
+#[test]
+fn scrolling() {
+    let editor = run("scroll.txt", &numbered(30), "<pagedown><down><down>");
+    assert_screen(&editor, r"
+line 9
+line 10
+line 11
+line 12
+line 13
+line 14
+scroll.txt            - 30 lines | UTF-8 | LF
+Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl");
+    assert_eq!(editor.screen.cursor(), (0, 5));
+}
+
+#[test]
+fn saving() {
+    let path = temp_file("save.txt", "first\n");
+    let (buffer, _) = Buffer::open(path.to_str().unwrap());
+    let editor = run_buffer(Config::default(), buffer, "<end> line<enter>second<ctrl-s>");
+    let saved = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(saved, "first line\nsecond\n");
+    assert_eq!(editor.screen.lines().last().unwrap(), "18 bytes written to disk successfully");
+}
+
+#[test]
+fn macro_playback() {
+    let editor = run("macro.txt", &numbered(5), "<f3>a<home>- <down><f3><f4>a 3<enter>");
+    assert_screen(&editor, r"
+- line 1
+- line 2
+- line 3
+- line 4
+line 5
+~
+macro.txt            (modified) - 5 lines | UTF-8
+");
+    assert_eq!(editor.screen.cursor(), (2, 4));
+}
+
+#[test]
+fn macros_playing_each_other() {
+    let editor = run("runaway.txt", "text\n", "<f3>a<home><f4>b<enter><f3><f3>b<f4>a<enter><f3><f4>a<enter>");
+    assert_eq!(editor.screen.lines().last().unwrap(), "Macro stopped! More than 1000000 keys to play");
+}
+
+#[test]
+fn macros_saved_and_loaded() {
+    let keys = parse_script("<ctrl-f>a<lt>b>c<enter><ctrl-lt><alt-x><f5>  A").unwrap();
+    let mut macros = Macros::default();
+    macros.set('q', keys.clone());
+
+    let path = temp_file("macros.toml", "");
+    macros.save(&path).unwrap();
+    let mut loaded = Macros::default();
+    let count = loaded.load(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(count, Ok(1));
+    assert_eq!(loaded.get('q'), Some(&keys[..]));
+}
+
+#[test]
+fn key_names_keep_case() {
+    let keys = parse_script("<alt-X><Alt-x><CTRL-S><F5><Enter>").unwrap();
+    assert_eq!(keys_to_script(&keys), "<alt-X><alt-x><ctrl-s><f5><enter>");
+    assert_eq!(parse_script(&keys_to_script(&keys)).unwrap(), keys);
+}
+
+#[test]
+fn substitute_in_range() {
+    let editor = run("subst.txt", "a a\nb a\na a\n", "<ctrl-o>2,$s/a/X/g<enter>");
+    assert_screen(&editor, r"
+a a
+b X
+X X
+~
+~
+~
+subst.txt            (modified) - 3 lines | UTF-8
+Replaced 3 occurrences on 2 lines");
+    assert_eq!(editor.screen.cursor(), (0, 2));
+}
+
+#[test]
+fn command_completion_and_history() {
+    let editor = run("history.txt", "one\none\none\n", "<ctrl-o>sub<tab>/one/two/<enter><down><ctrl-o><up><enter>");
+    assert_screen(&editor, r"
+two
+two
+one
+~
+~
+~
+history.txt          (modified) - 3 lines | UTF-8
+Replaced 1 occurrence on 1 line");
+    assert_eq!(editor.screen.cursor(), (0, 1));
+}
+
+#[test]
+fn set_option() {
+    let editor = run("set.txt", "one\ntwo\n", "<ctrl-o>set number nowrap<enter><ctrl-o>$<enter>");
+    assert_screen(&editor, r"
+1 one
+2 two
+  ~
+  ~
+  ~
+  ~
+set.txt               - 2 lines | UTF-8 | LF
+");
+    assert_eq!(editor.screen.cursor(), (2, 1));
+}
+
+#[test]
+fn byte_offset_in_line_ending() {
+    let editor = run("crlf.txt", "ab\r\ncd\r\n", "<ctrl-g>b3<enter>");
+    assert_eq!(editor.screen.cursor(), (2, 0));
+    let editor = run("crlf2.txt", "ab\r\ncd\r\n", "<ctrl-g>b4<enter><right>");
+    assert_eq!(editor.screen.cursor(), (1, 1));
+}
+
+#[test]
+fn substitute_at_line_start_and_end() {
+    let editor = run("edges.txt", "one\ntwo\nthree\n", "<ctrl-o>%s/^/> /<enter><ctrl-o>1,2s/$/;/<enter>");
+    assert_screen(&editor, r"
+> one;
+> two;
+> three
+~
+~
+~
+edges.txt            (modified) - 3 lines | UTF-8
+Replaced 2 occurrences on 2 lines");
+    assert_eq!(editor.screen.cursor(), (0, 1));
+}
+
+#[test]
+fn write_to_another_file() {
+    let path = temp_file("copy.txt", "old\n");
+    let script = format!("new <ctrl-o>w {0}<enter><ctrl-o>w! {0}<enter>", path.display());
+    let editor = run("original.txt", "text\n", &script);
+    let copy = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(copy, "new text\n");
+    assert_eq!(editor.screen.lines()[6], "original.txt         (modified) - 1 lines | UTF-8");
+
+    let path = temp_file("kept.txt", "old\n");
+    let editor = run("original.txt", "text\n", &format!("<ctrl-o>w {}<enter>", path.display()));
+    let kept = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(kept, "old\n");
+    assert_eq!(editor.screen.lines().last().unwrap(), "File exists (add ! to override)");
+}
+
+#[test]
+fn vi_normal_mode_keeps_editing_keys_out() {
+    let config = Config { vi_mode: true, ..Config::default() };
+    let editor = run_with(config, "vi.txt", "one\ntwo\n", "j<tab><ctrl-h><ctrl-x><ctrl-v>l");
+    assert_screen(&editor, r"
+one
+two
+~
+~
+~
+~
+NORMAL | vi.txt                - 2 lines | UTF-8 |
+Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl");
+    assert_eq!(editor.screen.cursor(), (1, 1));
+}
+
+#[test]
+fn status_bar_with_wide_filename() {
+    let editor = run("日本語のメモ.txt", "text\n", "");
+    assert_eq!(editor.screen.lines()[6], "日本語のメモ.txt      - 1 lines | UTF-8 | LF");
+    let name = format!("{}.txt", "長".repeat(30));
+    let editor = run(&name, "text\n", "");
+    assert_eq!(editor.screen.lines()[6], "長".repeat(25));
+}
+
+#[test]
+fn relative_goto_past_the_ends() {
+    let editor = run("far.txt", &numbered(3), "<ctrl-g>-9223372036854775808<enter>");
+    assert!(editor.screen.lines().last().unwrap().starts_with("Line -9223372036854775807 is out of range"));
+    let editor = run("far2.txt", &numbered(3), "<down><ctrl-g>+9223372036854775807<enter>");
+    assert!(editor.screen.lines().last().unwrap().starts_with("Line 9223372036854775809 is out of range"));
+    assert_eq!(editor.screen.cursor(), (0, 1));
+}
+
+#[test]
+fn vi_huge_counts() {
+    let config = Config { vi_mode: true, ..Config::default() };
+    let huge = "99999999999999999999";
+    let script = format!("l{0}lj{0}jk{0}ddyy{0}p{0}u{0}.", huge);
+    let editor = run_with(config, "counts.txt", &numbered(3), &script);
+    assert_screen(&editor, r"
+line 1
+line 2
+line 3
+~
+~
+~
+NORMAL | counts.txt            - 3 lines | UTF-8 |
+More than 1000000 keys to repeat");
+    assert_eq!(editor.screen.cursor(), (5, 1));
+
+    let config = Config { vi_mode: true, ..Config::default() };
+    let editor = run_with(config, "put.txt", &numbered(3), &format!("yy{}p", huge));
+    assert_eq!(editor.screen.lines().last().unwrap(), "Can't put more than 67108864 bytes");
+}
+
+#[test]
+fn mixed_line_endings() {
+    let editor = run("mixed.txt", "a\r\nb\nc\r\n", "");
+    assert_eq!(editor.screen.lines()[6], "mixed.txt             - 3 lines | UTF-8 | CRLF*");
+
+    let path = temp_file("mixed.txt", "a\r\nb\nc\r\n");
+    let (buffer, _) = Buffer::open(path.to_str().unwrap());
+    let editor = run_buffer(Config::default(), buffer, "<ctrl-s>n");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\r\nb\nc\r\n");
+    assert_eq!(editor.screen.lines()[7], "Save aborted");
+
+    let (buffer, _) = Buffer::open(path.to_str().unwrap());
+    let editor = run_buffer(Config::default(), buffer, "<ctrl-s>y");
+    let saved = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(saved, "a\r\nb\r\nc\r\n");
+    assert_eq!(editor.screen.lines()[7], "9 bytes written to disk successfully");
+}
+
+#[test]
+fn wrap_resets_every_window() {
+    let text = format!("{}\nshort\n", "long ".repeat(12));
+    let editor = run("split.txt", &text, "<ctrl-w>v<end><ctrl-w>w<ctrl-o>set wrap<enter>");
+    assert_screen(&editor, r"
+long long long long     |long long long long long
+↪ long long long long   |↪ long long long long
+↪ long long long long   |↪ long long long
+short                   |short
+~                       |~
+~                       |~
+split.txt             - |split.txt             - 2
+");
+}
+
+#[test]
+fn wide_characters_redrawn() {
+    // Every edit shifts the wide graphemes by one column, over the halves of the ones before
+    let editor = run("wide.txt", "日本語\nab\n", "x<left><delete><delete>y<down>日");
+    assert_screen(&editor, r"
+y本語
+a日b
+~
+~
+~
+~
+wide.txt             (modified) - 2 lines | UTF-8
+Help: Press Ctrl-q to exit | Ctrl-s to save | Ctrl");
+    assert_eq!(editor.screen.cursor(), (3, 1));
+}
+
+#[test]
+fn messages_name_rebound_keys() {
+    let mut config = Config::default();
+    config.keymap.bind("ctrl-q", "none").unwrap();
+    config.keymap.bind("alt-X", "quit").unwrap();
+    let editor = run_with(config, "keys.txt", "text\n", "");
+    assert_eq!(editor.screen.lines()[7], "Help: Press Alt-X to exit | Ctrl-s to save | Ctrl-");
+
+    let mut config = Config::default();
+    config.keymap.bind("ctrl-q", "none").unwrap();
+    config.keymap.bind("f10", "quit").unwrap();
+    let editor = run_with(config, "keys.txt", "text\n", "a<f10>");
+    assert_eq!(editor.screen.lines()[7], "Warning!! File has unsaved changes. Press F10 3 mo");
+
+    let mut config = Config::default();
+    config.keymap.bind("alt-X", "quit").unwrap();
+    let alt_shift_x = KeyEvent::new(KeyCode::Char('X'), KeyModifiers::ALT | KeyModifiers::SHIFT);
+    assert!(config.keymap.lookup(&alt_shift_x) == Some(Command::Quit));
+}
+
+#[test]
+fn command_line_addresses_past_the_ends() {
+    let editor = run("address.txt", &numbered(3), "<ctrl-o>.+9223372036854775807+5<enter>");
+    assert!(editor.screen.lines()[7].starts_with("Line 9223372036854775807 is out of range"));
+}
@@ -0,0 +1,326 @@
+use toml::Value;
+
+// Names the command prompt knows, for completing them
+const COMMANDS: &[&str] = &["e", "edit", "goto", "q", "quit", "s", "set", "substitute", "w", "wq", "write", "x"];
+
+// Commands which take a file, their argument is completed as a path
+const FILE_COMMANDS: &[&str] = &["e", "edit", "w", "write", "wq", "x"];
+
+// Short names for settings, like vi has them, next to the config names
+const OPTIONS: &[(&str, &str)] = &[
+    ("tabstop", "tab_width"),
+    ("ts", "tab_width"),
+    ("expandtab", "expand_tabs"),
+    ("et", "expand_tabs"),
+    ("number", "line_numbers"),
+    ("nu", "line_numbers"),
+    ("wrap", "soft_wrap")
+];
+
+// Lines a command keeps to the newest
+const HISTORY_LEN: usize = 100;
+
+// Where an address of a range starts from
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Base {
+    // Counted from 1
+    Line(usize),
+    // The line of the cursor, "."
+    Current,
+    // "$"
+    Last
+}
+
+// A line of a range like "12", ".+3" or "$-1"
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Address {
+    pub base: Base,
+    pub offset: isize
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Range {
+    // "%"
+    Whole,
+    // From one address to another, both the same for a single line
+    Lines(Address, Address)
+}
+
+// A line typed into the command prompt, like "w notes.txt", "q!" or "%s/a/b/g"
+pub struct ExCommand<'a> {
+    pub range: Option<Range>,
+    pub name: &'a str,
+    pub bang: bool,
+    pub arg: &'a str
+}
+
+// The pattern, replacement and flags of "s/pattern/replacement/flags"
+pub struct Substitute {
+    pub pattern: String,
+    pub replacement: String,
+    // g: every match in a line and not only the first one
+    pub global: bool,
+    // i
+    pub ignore_case: bool
+}
+
+impl ExCommand<'_> {
+    // Function to split a command line into its range, name and argument
+    pub fn parse(line: &str) -> Result<ExCommand<'_>, String> {
+        let (range, rest) = parse_range(line.trim_start())?;
+        let rest = rest.trim_start();
+        let name_len = rest.find(|ch: char| !ch.is_ascii_alphabetic()).unwrap_or(rest.len());
+        let (name, rest) = rest.split_at(name_len);
+        let (bang, rest) = match rest.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, rest)
+        };
+        Ok(ExCommand { range, name, bang, arg: rest.trim_start() })
+    }
+}
+
+fn parse_range(text: &str) -> Result<(Option<Range>, &str), String> {
+    if let Some(rest) = text.strip_prefix('%') {
+        return Ok((Some(Range::Whole), rest));
+    }
+    let Some((first, rest)) = parse_address(text)? else {
+        return Ok((None, text));
+    };
+    match rest.strip_prefix(',') {
+        Some(rest) => {
+            let (last, rest) = parse_address(rest)?
+                .ok_or_else(|| format!("The range has no end: {}", text))?;
+            Ok((Some(Range::Lines(first, last)), rest))
+        },
+        None => Ok((Some(Range::Lines(first, first)), rest))
+    }
+}
+
+// Function to read an address like "12", ".", "$" or ".+3" at the start of the text,
+// an offset on its own like "+3" counts from the cursor
+fn parse_address(text: &str) -> Result<Option<(Address, &str)>, String> {
+    let digits = text.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(text.len());
+    let (base, mut rest) = if digits > 0 {
+        let line = text[..digits].parse::<usize>().map_err(|_| format!("Not a line: {}", &text[..digits]))?;
+        if line == 0 {
+            return Err(String::from("Lines are counted from 1"));
+        }
+        (Base::Line(line), &text[digits..])
+    } else if let Some(rest) = text.strip_prefix('.') {
+        (Base::Current, rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (Base::Last, rest)
+    } else if text.starts_with(['+', '-']) {
+        (Base::Current, text)
+    } else {
+        return Ok(None);
+    };
+
+    let mut offset: isize = 0;
+    while let Some(sign) = rest.chars().next().filter(|ch| *ch == '+' || *ch == '-') {
+        rest = &rest[1..];
+        let digits = rest.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(rest.len());
+        // A sign on its own is one line
+        let count = match digits {
+            0 => 1,
+            _ => rest[..digits].parse::<isize>().map_err(|_| format!("Not a number of lines: {}", &rest[..digits]))?
+        };
+        rest = &rest[digits..];
+        // Too far either way stops at the ends of an isize, which are past any file
+        offset = if sign == '+' { offset.saturating_add(count) } else { offset.saturating_sub(count) };
+    }
+    Ok(Some((Address { base, offset }, rest)))
+}
+
+impl Substitute {
+    // Function to read "/pattern/replacement/flags", any character which isn't
+    // a letter, digit or blank can stand in for the '/'
+    // The separator is put in the pattern or the replacement with a '\' before it
+    pub fn parse(arg: &str) -> Result<Substitute, String> {
+        let Some(separator) = arg.chars().next().filter(|ch| !ch.is_alphanumeric() && !ch.is_whitespace() && *ch != '\\') else {
+            return Err(String::from("Usage: s/pattern/replacement/flags"));
+        };
+
+        let mut parts = vec![String::new()];
+        let mut chars = arg[separator.len_utf8()..].chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => match chars.next() {
+                    Some(next) if next == separator => parts.last_mut().unwrap().push(next),
+                    // Other escapes are for the pattern
+                    Some(next) => {
+                        parts.last_mut().unwrap().push('\\');
+                        parts.last_mut().unwrap().push(next);
+                    },
+                    None => parts.last_mut().unwrap().push('\\')
+                },
+                _ if ch == separator && parts.len() < 3 => parts.push(String::new()),
+                _ => parts.last_mut().unwrap().push(ch)
+            }
+        }
+
+        let mut parts = parts.into_iter();
+        let pattern = parts.next().unwrap_or_default();
+        if pattern.is_empty() {
+            return Err(String::from("The pattern is empty"));
+        }
+        let replacement = parts.next().unwrap_or_default();
+        let (mut global, mut ignore_case) = (false, false);
+        for flag in parts.next().unwrap_or_default().chars() {
+            match flag {
+                'g' => global = true,
+                'i' => ignore_case = true,
+                _ => return Err(format!("Unknown flag: {}", flag))
+            }
+        }
+        Ok(Substitute { pattern, replacement, global, ignore_case })
+    }
+}
+
+// Function to read one setting of "set", like "tabstop=4", "wrap" or "nowrap",
+// as the name of the setting in the config and its value
+pub fn parse_setting(text: &str) -> (&str, Value) {
+    if let Some((name, value)) = text.split_once('=') {
+        let value = match value {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => match value.parse::<i64>() {
+                Ok(number) => Value::Integer(number),
+                Err(_) => Value::String(value.trim_matches('"').to_string())
+            }
+        };
+        return (config_name(name), value);
+    }
+    match text.strip_prefix("no") {
+        // "nowrap", but not "no" on its own
+        Some(name) if !name.is_empty() => (config_name(name), Value::Boolean(false)),
+        _ => (config_name(text), Value::Boolean(true))
+    }
+}
+
+fn config_name(name: &str) -> &str {
+    OPTIONS.iter().find(|(short, _)| *short == name).map_or(name, |(_, key)| *key)
+}
+
+// What tab completion makes of a command line
+pub struct Completion {
+    pub line: String,
+    // Everything that fitted, when there was more than one
+    pub choices: Vec<String>
+}
+
+// Function to complete the command name, or the path after a command taking
+// a file, as far as all the names which fit agree
+pub fn complete(line: &str) -> Completion {
+    let unchanged = || Completion { line: line.to_string(), choices: Vec::new() };
+    let Ok((_, rest)) = parse_range(line.trim_start()) else {
+        return unchanged();
+    };
+    let rest = rest.trim_start();
+    let name_len = rest.find(|ch: char| !ch.is_ascii_alphabetic()).unwrap_or(rest.len());
+    let (name, arg) = rest.split_at(name_len);
+
+    if arg.is_empty() {
+        let names = COMMANDS.iter()
+            .filter(|command| command.starts_with(name))
+            .map(|command| command.to_string())
+            .collect::<Vec<String>>();
+        if names.is_empty() {
+            return unchanged();
+        }
+        return fill_in(&line[..line.len() - name.len()], names, " ");
+    }
+    if !FILE_COMMANDS.contains(&name) {
+        return unchanged();
+    }
+
+    let typed = arg.strip_prefix('!').unwrap_or(arg).trim_start();
+    let head = &line[..line.len() - typed.len()];
+    let dir = &typed[..typed.rfind('/').map_or(0, |at| at + 1)];
+    let Ok(entries) = std::fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return unchanged();
+    };
+    let prefix = &typed[dir.len()..];
+    let mut names = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files only when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            Some(if entry.path().is_dir() { name + "/" } else { name })
+        })
+        .collect::<Vec<String>>();
+    if names.is_empty() {
+        return unchanged();
+    }
+    names.sort();
+    fill_in(&format!("{}{}", head, dir), names, "")
+}
+
+// Function to put what all the choices start with after the head, a single
+// choice goes in whole with the end after it, unless it is a directory
+fn fill_in(head: &str, choices: Vec<String>, end: &str) -> Completion {
+    let first = &choices[0];
+    if choices.len() == 1 {
+        let end = if first.ends_with('/') { "" } else { end };
+        return Completion { line: format!("{}{}{}", head, first, end), choices: Vec::new() };
+    }
+
+    let shared = choices[1..].iter().fold(first.len(), |shared, choice| {
+        let same = first.char_indices()
+            .zip(choice.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((at, a), _)| at + a.len_utf8());
+        same.min(shared)
+    });
+    Completion { line: format!("{}{}", head, &first[..shared]), choices }
+}
+
+// Command lines run before, walked through with Up and Down
+#[derive(Default)]
+pub struct CommandHistory {
+    lines: Vec<String>,
+    // Line being shown, lines.len() for the one typed before walking back
+    at: usize,
+    typed: String
+}
+
+impl CommandHistory {
+    pub fn push(&mut self, line: &str) {
+        if !line.trim().is_empty() && self.lines.last().map(String::as_str) != Some(line) {
+            self.lines.push(line.to_string());
+            if self.lines.len() > HISTORY_LEN {
+                self.lines.remove(0);
+            }
+        }
+        self.at = self.lines.len();
+    }
+
+    // Function to start walking from the newest line again
+    pub fn reset(&mut self) {
+        self.at = self.lines.len();
+    }
+
+    // Function to step back to an older line, what is typed is kept to come back to
+    pub fn prev(&mut self, typed: &str) -> Option<&str> {
+        if self.at == 0 {
+            return None;
+        }
+        if self.at == self.lines.len() {
+            self.typed = typed.to_string();
+        }
+        self.at -= 1;
+        Some(&self.lines[self.at])
+    }
+
+    pub fn next(&mut self) -> Option<&str> {
+        if self.at >= self.lines.len() {
+            return None;
+        }
+        self.at += 1;
+        Some(self.lines.get(self.at).unwrap_or(&self.typed))
+    }
+}
@@ -0,0 +1,263 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Function to save data without ever leaving a half written file behind.
+// The data goes to a temporary file next to the target, which is flushed
+// to disk and then renamed over the target, so the original stays intact
+// until the new contents are safely stored.
+pub fn write_atomic<P: AsRef<Path>>(path: P, data: &[u8]) -> io::Result<()> {
+    // Writing through a symlink should update the file it points to
+    let target = match fs::canonicalize(path.as_ref()) {
+        Ok(target) => target,
+        Err(_) => path.as_ref().to_path_buf(),
+    };
+    let original = fs::metadata(&target).ok();
+    let tmp = temp_path(&target);
+
+    let result = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(data)?;
+        if let Some(meta) = &original {
+            fs::set_permissions(&tmp, meta.permissions())?;
+            preserve_owner(&tmp, meta);
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &target)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+
+    // Making the rename itself durable
+    if let Some(dir) = target.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+// Hidden sibling of the target, e.g. dir/.name.kilo-1234.tmp
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    target.with_file_name(format!(".{}.kilo-{}.tmp", name, std::process::id()))
+}
+
+// Giving the new file the owner of the old one. Only possible when we are
+// allowed to, otherwise the file just belongs to whoever saved it.
+#[cfg(unix)]
+fn preserve_owner(tmp: &Path, meta: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    let _ = std::os::unix::fs::chown(tmp, Some(meta.uid()), Some(meta.gid()));
+}
+
+#[cfg(not(unix))]
+fn preserve_owner(_tmp: &Path, _meta: &fs::Metadata) {}
+
+// Line ending style of a file
+#[derive(Copy, Clone, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r"
+        }
+    }
+
+    // Name shown in the status bar
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+            LineEnding::Cr => "CR"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "lf" | "unix" => Some(LineEnding::Lf),
+            "crlf" | "dos" => Some(LineEnding::CrLf),
+            "cr" | "mac" => Some(LineEnding::Cr),
+            _ => None
+        }
+    }
+}
+
+// Function to split text into lines ending with any of \r\n, \n or \r.
+// Returns the lines, the line ending used the most, whether the
+// last line is terminated too and whether other endings were found as well.
+pub fn split_lines(text: &str) -> (Vec<String>, LineEnding, bool, bool) {
+    let mut lines = Vec::new();
+    let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+    let mut start = 0;
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let len = match bytes[i] {
+            b'\r' if bytes.get(i + 1) == Some(&b'\n') => { crlf += 1; 2 },
+            b'\r' => { cr += 1; 1 },
+            b'\n' => { lf += 1; 1 },
+            _ => { i += 1; continue; }
+        };
+        lines.push(text[start..i].to_string());
+        i += len;
+        start = i;
+    }
+
+    // An empty file counts as terminated, so that typing into it gets a newline on save
+    let final_newline = start == text.len();
+    if !final_newline {
+        lines.push(text[start..].to_string());
+    }
+
+    let ending = if crlf > lf && crlf >= cr {
+        LineEnding::CrLf
+    } else if cr > lf && cr > crlf {
+        LineEnding::Cr
+    } else {
+        LineEnding::Lf
+    };
+
+    let mixed = [lf, crlf, cr].iter().filter(|&&count| count > 0).count() > 1;
+    (lines, ending, final_newline, mixed)
+}
+
+// Text encoding of a file, kept so that saving writes back the same bytes
+#[derive(Copy, Clone, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    // UTF-16 without a BOM, guessed from the NUL bytes
+    Utf16Le,
+    Utf16Be,
+    Utf16LeBom,
+    Utf16BeBom,
+    Latin1
+}
+
+impl Encoding {
+    // Name shown in the status bar
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Utf16LeBom => "UTF-16LE BOM",
+            Encoding::Utf16BeBom => "UTF-16BE BOM",
+            Encoding::Latin1 => "Latin-1"
+        }
+    }
+
+    // Function to turn the text back into bytes of this encoding
+    pub fn encode(&self, text: &str) -> std::result::Result<Vec<u8>, String> {
+        match self {
+            Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+            Encoding::Utf8Bom => {
+                let mut bytes = vec![0xEF, 0xBB, 0xBF];
+                bytes.extend_from_slice(text.as_bytes());
+                Ok(bytes)
+            },
+            Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()).collect()),
+            Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()).collect()),
+            Encoding::Utf16LeBom => {
+                let mut bytes = vec![0xFF, 0xFE];
+                bytes.extend(text.encode_utf16().flat_map(|unit| unit.to_le_bytes()));
+                Ok(bytes)
+            },
+            Encoding::Utf16BeBom => {
+                let mut bytes = vec![0xFE, 0xFF];
+                bytes.extend(text.encode_utf16().flat_map(|unit| unit.to_be_bytes()));
+                Ok(bytes)
+            },
+            Encoding::Latin1 => text
+                .chars()
+                .map(|c| u8::try_from(c)
+                     .map_err(|_| format!("'{}' can't be written in Latin-1", c)))
+                .collect()
+        }
+    }
+}
+
+// Contents of a file turned into text
+pub struct Decoded {
+    pub text: String,
+    pub encoding: Encoding,
+    // Set when the file has NUL bytes, so it's most likely not text at all
+    pub binary: bool
+}
+
+// Function to guess the encoding of a file and decode it.
+// Anything which isn't valid UTF-8 or UTF-16 is read as Latin-1, where every
+// byte is a character, so that saving gives back the exact same bytes.
+pub fn decode(bytes: &[u8]) -> Decoded {
+    let found = if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        std::str::from_utf8(rest).ok().map(|text| (text.to_string(), Encoding::Utf8Bom))
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        decode_utf16(rest, Encoding::Utf16LeBom)
+    } else if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        decode_utf16(rest, Encoding::Utf16BeBom)
+    } else if bytes.contains(&0) {
+        guess_utf16(bytes)
+    } else {
+        std::str::from_utf8(bytes).ok().map(|text| (text.to_string(), Encoding::Utf8))
+    };
+
+    match found {
+        Some((text, encoding)) => Decoded { text, encoding, binary: false },
+        None => Decoded {
+            text: bytes.iter().map(|&b| b as char).collect(),
+            encoding: Encoding::Latin1,
+            binary: bytes.contains(&0)
+        }
+    }
+}
+
+fn decode_utf16(bytes: &[u8], encoding: Encoding) -> Option<(String, Encoding)> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| match encoding {
+            Encoding::Utf16Be | Encoding::Utf16BeBom => u16::from_be_bytes([pair[0], pair[1]]),
+            _ => u16::from_le_bytes([pair[0], pair[1]])
+        })
+        .collect::<Vec<u16>>();
+    String::from_utf16(&units).ok().map(|text| (text, encoding))
+}
+
+// UTF-16 without a BOM: mostly ASCII text has a NUL in every other byte
+fn guess_utf16(bytes: &[u8]) -> Option<(String, Encoding)> {
+    let even = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    let half = bytes.len() / 2;
+
+    let encoding = if even == 0 && odd > half / 2 {
+        Encoding::Utf16Le
+    } else if odd == 0 && even > half / 2 {
+        Encoding::Utf16Be
+    } else {
+        return None;
+    };
+
+    decode_utf16(bytes, encoding).filter(|(text, _)| !text.contains('\0'))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,19 @@
+use super::*;
+
+// Function to check that a file decodes as the encoding and saves back to the same bytes
+fn round_trip(bytes: &[u8], encoding: Encoding) {
+    let decoded = decode(bytes);
+    assert!(decoded.encoding == encoding, "{} read as {}", encoding.name(), decoded.encoding.name());
+    assert_eq!(decoded.encoding.encode(&decoded.text).unwrap(), bytes, "{}", encoding.name());
+}
+
+#[test]
+fn encodings_round_trip() {
+    round_trip("hé\n".as_bytes(), Encoding::Utf8);
+    round_trip(b"\xEF\xBB\xBFh\xC3\xA9\n", Encoding::Utf8Bom);
+    round_trip(b"h\0i\0\n\0", Encoding::Utf16Le);
+    round_trip(b"\0h\0i\0\n", Encoding::Utf16Be);
+    round_trip(b"\xFF\xFEh\0i\0\n\0", Encoding::Utf16LeBom);
+    round_trip(b"\xFE\xFF\0h\0i\0\n", Encoding::Utf16BeBom);
+    round_trip(b"h\xE9\n", Encoding::Latin1);
+}
@@ -0,0 +1,49 @@
+// Where the go-to prompt jumps, as typed there
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Target {
+    // A line and maybe a column, both counted from 1
+    Line(usize, Option<usize>),
+    // Lines down from the cursor, or up for a negative count
+    Relative(isize),
+    // Part of the way through the file
+    Percent(usize),
+    // Offset into the text of the file, counted from 0 in bytes of UTF-8
+    // without a BOM, whatever the encoding of the file is
+    Byte(usize)
+}
+
+impl Target {
+    // Function to read "42", "42:7", "+5", "-3", "50%" or "b1024"
+    pub fn parse(text: &str) -> Result<Target, String> {
+        let text = text.trim();
+        let number = |digits: &str| digits.trim().parse::<usize>()
+            .map_err(|_| format!("Not a line, +N, -N, N% or bN: {}", text));
+
+        // Counts too big for an isize stop at its ends, which are past any file anyway
+        if let Some(count) = text.strip_prefix('+') {
+            return Ok(Target::Relative(0isize.saturating_add_unsigned(number(count)?)));
+        }
+        if let Some(count) = text.strip_prefix('-') {
+            return Ok(Target::Relative(0isize.saturating_sub_unsigned(number(count)?)));
+        }
+        if let Some(percent) = text.strip_suffix('%') {
+            let percent = number(percent)?;
+            if percent > 100 {
+                return Err(format!("{}% is more than the whole file", percent));
+            }
+            return Ok(Target::Percent(percent));
+        }
+        if let Some(offset) = text.strip_prefix('b') {
+            return Ok(Target::Byte(number(offset)?));
+        }
+
+        let (line, column) = match text.split_once(':') {
+            Some((line, column)) => (number(line)?, Some(number(column)?)),
+            None => (number(text)?, None)
+        };
+        if line == 0 || column == Some(0) {
+            return Err(String::from("Lines and columns are counted from 1"));
+        }
+        Ok(Target::Line(line, column))
+    }
+}
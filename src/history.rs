@@ -0,0 +1,186 @@
+use kilo_ed_rust::*;
+
+// Position of an edit inside the rows.
+// Unlike the cursor, it counts bytes of Row::characters and not graphemes,
+// so that undoing an edit removes exactly what was inserted even if a
+// combining mark got merged with the character before it.
+#[derive(Copy, Clone, PartialEq)]
+pub struct TextPos {
+    pub row: usize,
+    pub byte: usize,
+}
+
+impl TextPos {
+    // Function to find where text inserted here ends, a '\n' in it
+    // moves the end to the start of a later row
+    pub fn after(&self, text: &str) -> TextPos {
+        let lines = text.matches('\n').count();
+        let last_len = text.rsplit('\n').next().unwrap_or_default().len();
+        TextPos {
+            row: self.row + lines,
+            byte: if lines == 0 { self.byte + last_len } else { last_len }
+        }
+    }
+}
+
+// A single change to the rows of the editor
+#[derive(Clone)]
+pub enum Edit {
+    // Text inserted at a position, a '\n' in it splits the row
+    Insert { at: TextPos, text: String },
+    // Text removed from a position, a '\n' in it joins two rows
+    Delete { at: TextPos, text: String },
+    // A whole row inserted at an index, like when typing past the last row
+    InsertRow { at: usize, text: String },
+    DeleteRow { at: usize, text: String },
+}
+
+impl Edit {
+    // Returns the edit that takes back this one
+    pub fn inverse(&self) -> Edit {
+        match self.clone() {
+            Edit::Insert { at, text } => Edit::Delete { at, text },
+            Edit::Delete { at, text } => Edit::Insert { at, text },
+            Edit::InsertRow { at, text } => Edit::DeleteRow { at, text },
+            Edit::DeleteRow { at, text } => Edit::InsertRow { at, text },
+        }
+    }
+}
+
+// Edits which are undone and redone together, like a run of typed characters
+#[derive(Clone)]
+pub struct UndoStep {
+    pub edits: Vec<Edit>,
+    pub cursor_before: CursorPos,
+    pub cursor_after: CursorPos,
+    typing: bool,
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<UndoStep>,
+    redo: Vec<UndoStep>,
+    current: Option<UndoStep>,
+    depth: usize,
+    // Length of the undo stack when the file was saved.
+    // None once that state can't be reached anymore.
+    saved: Option<usize>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            saved: Some(0),
+            ..Default::default()
+        }
+    }
+
+    // Opens a step, every edit recorded till the matching commit() goes in it.
+    // Calls can be nested, only the outermost pair makes a step.
+    // With typing set, the step continues the previous one if that was typing
+    // too and ended where the cursor is now.
+    pub fn begin(&mut self, cursor: CursorPos, typing: bool) {
+        self.depth += 1;
+        if self.depth > 1 {
+            return;
+        }
+
+        let continues = typing
+            && self.saved != Some(self.undo.len())
+            && self.undo.last().is_some_and(|last| {
+                last.typing && last.cursor_after == cursor
+            });
+
+        self.current = if continues {
+            self.undo.pop()
+        } else {
+            Some(UndoStep {
+                edits: Vec::new(),
+                cursor_before: cursor,
+                cursor_after: cursor,
+                typing,
+            })
+        };
+    }
+
+    pub fn record(&mut self, edit: Edit) {
+        if let Some(step) = self.current.as_mut() {
+            step.edits.push(edit);
+
+            // A new edit makes the redo steps meaningless
+            if self.saved.is_some_and(|saved| saved > self.undo.len()) {
+                self.saved = None;
+            }
+            self.redo.clear();
+        }
+    }
+
+    pub fn commit(&mut self, cursor: CursorPos) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth > 0 {
+            return;
+        }
+
+        if let Some(mut step) = self.current.take() {
+            if !step.edits.is_empty() {
+                step.cursor_after = cursor;
+                self.undo.push(step);
+            }
+        }
+    }
+
+    // Returns the step to take back, its edits have to be applied inversed and in reverse
+    pub fn undo(&mut self) -> Option<UndoStep> {
+        let step = self.undo.pop()?;
+        self.redo.push(step.clone());
+        Some(step)
+    }
+
+    pub fn redo(&mut self) -> Option<UndoStep> {
+        let step = self.redo.pop()?;
+        self.undo.push(step.clone());
+        Some(step)
+    }
+
+    // Number of steps which can be undone
+    pub fn steps(&self) -> usize {
+        self.undo.len()
+    }
+
+    // Joins the steps from index `from` on into one, so that everything done
+    // since then is undone together
+    pub fn join_since(&mut self, from: usize) {
+        if from + 1 >= self.undo.len() {
+            return;
+        }
+
+        let steps = self.undo.split_off(from);
+        let joined = UndoStep {
+            edits: steps.iter().flat_map(|step| step.edits.iter().cloned()).collect(),
+            cursor_before: steps[0].cursor_before,
+            cursor_after: steps[steps.len() - 1].cursor_after,
+            typing: false,
+        };
+        // A save in between the joined steps can't be got back to anymore
+        self.saved = match self.saved {
+            Some(saved) if saved <= from => Some(saved),
+            Some(saved) if saved == from + steps.len() => Some(from + 1),
+            _ => None,
+        };
+        self.undo.push(joined);
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.undo.len());
+    }
+
+    // For changes which are not undoable, like converting line endings
+    pub fn forget_saved(&mut self) {
+        self.saved = None;
+    }
+
+    // True if the rows are back to what was last saved
+    pub fn is_clean(&self) -> bool {
+        self.saved == Some(self.undo.len())
+    }
+}
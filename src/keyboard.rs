@@ -1,21 +1,100 @@
-use crossterm::event::{read, Event::*, KeyEvent};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+
+use crossterm::event::{Event, Event::*, KeyEvent};
+
+use crate::backend::*;
 
 use kilo_ed_rust::*;
 
-pub struct Keyboard;
+// Keys macros may play in one go, a macro which plays itself stops there
+pub const MAX_PLAYBACK: usize = 1_000_000;
+
+pub struct Keyboard {
+    input: Box<dyn Input>,
+    // Last size the terminal was resized to while only keys were wanted
+    resized: Option<(u16, u16)>,
+    // Keys read from the input since recording a macro started
+    recording: Option<Vec<KeyEvent>>,
+    // Keys of macros being played, read before the input
+    queued: VecDeque<KeyEvent>,
+    // Keys played since the input was last read
+    played: usize
+}
 
 impl Keyboard {
+    pub fn new(input: Box<dyn Input>) -> Self {
+        Self { input, resized: None, recording: None, queued: VecDeque::new(), played: 0 }
+    }
+
     // Function that waits for one keypress and return it.
-    pub fn read_key(&self) -> EditorResult<KeyEvent, ResultCode> {
+    pub fn read_key(&mut self) -> EditorResult<KeyEvent, ResultCode> {
         loop{
-            if let Ok(event) = read() {
-                if let Key(key_event) = event {
-                    return Ok(key_event);
-                }
-            } else {
-                return Err(ResultCode::KeyReadFail);
+            match self.read_event()? {
+                Key(key_event) => return Ok(key_event),
+                Resize(columns, rows) => self.resized = Some((columns, rows)),
+                Mouse(_) => {}
             }
         }
     }
-}
 
+    // Function that waits for a keypress, a mouse event or a resize
+    pub fn read_event(&mut self) -> EditorResult<Event, ResultCode> {
+        if let Some(key) = self.queued.pop_front() {
+            self.played += 1;
+            return Ok(Key(key));
+        }
+        self.played = 0;
+
+        let event = self.input.read().map_err(|err| match err.kind() {
+            ErrorKind::UnexpectedEof => ResultCode::InputEnded,
+            _ => ResultCode::KeyReadFail
+        })?;
+        if let (Key(key), Some(keys)) = (&event, &mut self.recording) {
+            keys.push(*key);
+        }
+        Ok(event)
+    }
+
+    // The size the terminal got while read_key() was waiting, if it changed
+    pub fn take_resize(&mut self) -> Option<(u16, u16)> {
+        self.resized.take()
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    // Function to end recording, the key which ended it is left out
+    pub fn stop_recording(&mut self) -> Vec<KeyEvent> {
+        let mut keys = self.recording.take().unwrap_or_default();
+        keys.pop();
+        keys
+    }
+
+    // Function to play keys the given number of times, before the rest
+    // of a macro being played
+    pub fn play(&mut self, keys: &[KeyEvent], times: usize) -> EditorResult<(), String> {
+        let total = keys.len().saturating_mul(times);
+        if self.played + self.queued.len() + total > MAX_PLAYBACK {
+            self.queued.clear();
+            return Err(format!("More than {} keys to play", MAX_PLAYBACK));
+        }
+        for _ in 0..times {
+            for key in keys.iter().rev() {
+                self.queued.push_front(*key);
+            }
+        }
+        Ok(())
+    }
+
+    // A macro is being played, the last key came from it
+    pub fn playing(&self) -> bool {
+        self.played > 0
+    }
+
+    // More keys of a macro are waiting
+    pub fn has_queued(&self) -> bool {
+        !self.queued.is_empty()
+    }
+}
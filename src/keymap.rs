@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+// Everything a key can be bound to
+// A new command gets a name in COMMANDS and a case in Editor::run_command()
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Command {
+    Quit,
+    Save,
+    Find,
+    Replace,
+    GoTo,
+    Undo,
+    Redo,
+    LineEnding,
+    NextBuffer,
+    PrevBuffer,
+    PickBuffer,
+    Window,
+    NewLine,
+    InsertTab,
+    DeleteBack,
+    DeleteForward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    LineStart,
+    LineEnd,
+    PageUp,
+    PageDown,
+    SelectLeft,
+    SelectRight,
+    SelectUp,
+    SelectDown,
+    SelectLineStart,
+    SelectLineEnd,
+    SelectPageUp,
+    SelectPageDown,
+    Cut,
+    Copy,
+    Paste,
+    LineNumbers,
+    SoftWrap,
+    RecordMacro,
+    PlayMacro,
+    SaveMacros,
+    LoadMacros,
+    Ex
+}
+
+// Names of the commands in the config
+const COMMANDS: &[(&str, Command)] = &[
+    ("quit", Command::Quit),
+    ("save", Command::Save),
+    ("find", Command::Find),
+    ("replace", Command::Replace),
+    ("goto", Command::GoTo),
+    ("undo", Command::Undo),
+    ("redo", Command::Redo),
+    ("line-ending", Command::LineEnding),
+    ("next-buffer", Command::NextBuffer),
+    ("prev-buffer", Command::PrevBuffer),
+    ("pick-buffer", Command::PickBuffer),
+    ("window", Command::Window),
+    ("new-line", Command::NewLine),
+    ("insert-tab", Command::InsertTab),
+    ("delete-back", Command::DeleteBack),
+    ("delete-forward", Command::DeleteForward),
+    ("move-left", Command::MoveLeft),
+    ("move-right", Command::MoveRight),
+    ("move-up", Command::MoveUp),
+    ("move-down", Command::MoveDown),
+    ("line-start", Command::LineStart),
+    ("line-end", Command::LineEnd),
+    ("page-up", Command::PageUp),
+    ("page-down", Command::PageDown),
+    ("select-left", Command::SelectLeft),
+    ("select-right", Command::SelectRight),
+    ("select-up", Command::SelectUp),
+    ("select-down", Command::SelectDown),
+    ("select-line-start", Command::SelectLineStart),
+    ("select-line-end", Command::SelectLineEnd),
+    ("select-page-up", Command::SelectPageUp),
+    ("select-page-down", Command::SelectPageDown),
+    ("cut", Command::Cut),
+    ("copy", Command::Copy),
+    ("paste", Command::Paste),
+    ("line-numbers", Command::LineNumbers),
+    ("soft-wrap", Command::SoftWrap),
+    ("record-macro", Command::RecordMacro),
+    ("play-macro", Command::PlayMacro),
+    ("save-macros", Command::SaveMacros),
+    ("load-macros", Command::LoadMacros),
+    ("command-line", Command::Ex)
+];
+
+// Names of the keys which aren't a single character
+const KEYS: &[(&str, KeyCode)] = &[
+    ("enter", KeyCode::Enter),
+    ("tab", KeyCode::Tab),
+    ("backspace", KeyCode::Backspace),
+    ("delete", KeyCode::Delete),
+    ("insert", KeyCode::Insert),
+    ("esc", KeyCode::Esc),
+    ("left", KeyCode::Left),
+    ("right", KeyCode::Right),
+    ("up", KeyCode::Up),
+    ("down", KeyCode::Down),
+    ("home", KeyCode::Home),
+    ("end", KeyCode::End),
+    ("pageup", KeyCode::PageUp),
+    ("pagedown", KeyCode::PageDown),
+    ("backtab", KeyCode::BackTab),
+    ("space", KeyCode::Char(' ')),
+    // For key scripts, where the name of a key goes between < and >
+    ("lt", KeyCode::Char('<')),
+    ("gt", KeyCode::Char('>'))
+];
+
+impl Command {
+    // Commands which leave the selection alone, any other one ends it
+    pub fn keeps_selection(&self) -> bool {
+        matches!(self, Command::SelectLeft | Command::SelectRight | Command::SelectUp
+                     | Command::SelectDown | Command::SelectLineStart | Command::SelectLineEnd
+                     | Command::SelectPageUp | Command::SelectPageDown | Command::Copy)
+    }
+
+    // Commands which change the text as a key is typed, vi's normal mode has its own keys for that
+    pub fn edits_text(&self) -> bool {
+        matches!(self, Command::NewLine | Command::InsertTab | Command::DeleteBack
+                     | Command::DeleteForward | Command::Cut | Command::Paste)
+    }
+
+    pub fn from_name(name: &str) -> Option<Command> {
+        COMMANDS.iter().find(|(n, _)| *n == name).map(|(_, command)| *command)
+    }
+}
+
+// Which command each key runs
+pub struct Keymap {
+    bindings: HashMap<KeyEvent, Command>
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let ctrl = |ch| KeyEvent::new(KeyCode::Char(ch), KeyModifiers::CONTROL);
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        let shift = |code| KeyEvent::new(code, KeyModifiers::SHIFT);
+
+        let bindings = [
+            (ctrl('q'), Command::Quit),
+            (ctrl('s'), Command::Save),
+            (ctrl('f'), Command::Find),
+            (ctrl('r'), Command::Replace),
+            (ctrl('g'), Command::GoTo),
+            (ctrl('z'), Command::Undo),
+            (ctrl('y'), Command::Redo),
+            (ctrl('e'), Command::LineEnding),
+            (ctrl('n'), Command::NextBuffer),
+            (ctrl('p'), Command::PrevBuffer),
+            (ctrl('b'), Command::PickBuffer),
+            (ctrl('w'), Command::Window),
+            (ctrl('h'), Command::DeleteBack),
+            (key(KeyCode::Backspace), Command::DeleteBack),
+            (key(KeyCode::Delete), Command::DeleteForward),
+            (key(KeyCode::Enter), Command::NewLine),
+            (key(KeyCode::Tab), Command::InsertTab),
+            (key(KeyCode::Left), Command::MoveLeft),
+            (key(KeyCode::Right), Command::MoveRight),
+            (key(KeyCode::Up), Command::MoveUp),
+            (key(KeyCode::Down), Command::MoveDown),
+            (key(KeyCode::Home), Command::LineStart),
+            (key(KeyCode::End), Command::LineEnd),
+            (key(KeyCode::PageUp), Command::PageUp),
+            (key(KeyCode::PageDown), Command::PageDown),
+            (shift(KeyCode::Left), Command::SelectLeft),
+            (shift(KeyCode::Right), Command::SelectRight),
+            (shift(KeyCode::Up), Command::SelectUp),
+            (shift(KeyCode::Down), Command::SelectDown),
+            (shift(KeyCode::Home), Command::SelectLineStart),
+            (shift(KeyCode::End), Command::SelectLineEnd),
+            (shift(KeyCode::PageUp), Command::SelectPageUp),
+            (shift(KeyCode::PageDown), Command::SelectPageDown),
+            (ctrl('x'), Command::Cut),
+            (ctrl('c'), Command::Copy),
+            (ctrl('v'), Command::Paste),
+            (key(KeyCode::F(3)), Command::RecordMacro),
+            (key(KeyCode::F(4)), Command::PlayMacro),
+            (ctrl('o'), Command::Ex)
+        ];
+        Self { bindings: bindings.into_iter().collect() }
+    }
+}
+
+impl Keymap {
+    // Function to find the command of a key
+    // Keys like the arrows also work with modifiers nothing else is bound to
+    pub fn lookup(&self, key: &KeyEvent) -> Option<Command> {
+        if let Some(command) = self.bindings.get(key) {
+            return Some(*command);
+        }
+        match key.code {
+            // Shift is already in the character, terminals send Alt-X as Alt-Shift-X
+            KeyCode::Char(_) if key.modifiers.contains(KeyModifiers::SHIFT) => {
+                self.bindings.get(&KeyEvent::new(key.code, key.modifiers - KeyModifiers::SHIFT)).copied()
+            },
+            KeyCode::Char(_) => None,
+            code => self.bindings.get(&KeyEvent::new(code, KeyModifiers::NONE)).copied()
+        }
+    }
+
+    // Function to bind a key like "ctrl-s" to a command like "save",
+    // the command "none" takes the key back
+    pub fn bind(&mut self, key: &str, command: &str) -> Result<(), String> {
+        let key = parse_key(key).ok_or_else(|| format!("unknown key '{}'", key))?;
+        if command == "none" {
+            self.bindings.remove(&key);
+            return Ok(());
+        }
+
+        let command = Command::from_name(command)
+            .ok_or_else(|| format!("unknown command '{}'", command))?;
+        self.bindings.insert(key, command);
+        Ok(())
+    }
+
+    // Function to name a key which runs the command, like "Ctrl-q" for the
+    // messages, or None when no key is bound to it
+    pub fn key_for(&self, command: Command) -> Option<String> {
+        self.bindings.iter()
+            .filter(|(_, bound)| **bound == command)
+            .filter_map(|(key, _)| key_name(key))
+            // The shortest name, and the same one every time
+            .min_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)))
+            .map(|name| display_name(&name))
+    }
+
+    // The help shown at the start, with the keys the main commands are bound to
+    pub fn help(&self) -> String {
+        let parts = [(Command::Quit, "exit"), (Command::Save, "save"), (Command::Find, "find")]
+            .iter()
+            .filter_map(|(command, what)| Some(format!("{} to {}", self.key_for(*command)?, what)))
+            .collect::<Vec<String>>();
+        if parts.is_empty() {
+            return String::new();
+        }
+        format!("Help: Press {}", parts.join(" | "))
+    }
+}
+
+// Function to read a key like "ctrl-s", "alt-shift-left", "f5" or "pagedown"
+pub fn parse_key(name: &str) -> Option<KeyEvent> {
+    // The last character is never a separator, so "ctrl--" is Ctrl and the minus key
+    let last = name.char_indices().last().map_or(0, |(at, _)| at);
+    let (prefix, key) = match name[..last].rfind('-') {
+        Some(at) => (&name[..at], &name[at + 1..]),
+        None => ("", name)
+    };
+
+    let mut modifiers = KeyModifiers::NONE;
+    for part in prefix.to_lowercase().split('-').filter(|part| !part.is_empty()) {
+        modifiers |= match part {
+            "ctrl" | "c" => KeyModifiers::CONTROL,
+            "alt" | "a" | "m" => KeyModifiers::ALT,
+            "shift" | "s" => KeyModifiers::SHIFT,
+            _ => return None
+        };
+    }
+
+    // A single character keeps its case, "alt-X" isn't "alt-x", but terminals
+    // send Ctrl with a letter the same either way
+    let mut chars = key.chars();
+    let code = match (chars.next(), chars.next()) {
+        (Some(ch), None) if modifiers.contains(KeyModifiers::CONTROL) => KeyCode::Char(ch.to_ascii_lowercase()),
+        (Some(ch), None) => KeyCode::Char(ch),
+        _ => {
+            let key = key.to_lowercase();
+            match KEYS.iter().find(|(n, _)| *n == key) {
+                Some((_, code)) => *code,
+                None => KeyCode::F(key.strip_prefix('f')?.parse().ok().filter(|n| (1..=12).contains(n))?)
+            }
+        }
+    };
+    Some(KeyEvent::new(code, modifiers))
+}
+
+// Function to name a key the way parse_key() reads it, like "ctrl-s" or "f5"
+pub fn key_name(key: &KeyEvent) -> Option<String> {
+    let name = match KEYS.iter().find(|(_, code)| *code == key.code) {
+        Some((name, _)) => name.to_string(),
+        None => match key.code {
+            KeyCode::Char(ch) => ch.to_string(),
+            KeyCode::F(n) => format!("f{}", n),
+            _ => return None
+        }
+    };
+
+    let mut prefix = String::new();
+    for (modifier, part) in [(KeyModifiers::CONTROL, "ctrl-"), (KeyModifiers::ALT, "alt-"), (KeyModifiers::SHIFT, "shift-")] {
+        // Shift is already in the character, 'A' and not shift-a
+        if key.modifiers.contains(modifier) && !(modifier == KeyModifiers::SHIFT && matches!(key.code, KeyCode::Char(_))) {
+            prefix.push_str(part);
+        }
+    }
+    Some(prefix + &name)
+}
+
+// Function to write a key name from key_name() for the user, "ctrl-alt-x" as
+// "Ctrl-Alt-x" and "pagedown" as "Pagedown", a character keeps its case
+fn display_name(name: &str) -> String {
+    let capital = |part: &str| {
+        let mut chars = part.chars();
+        chars.next().map_or(String::new(), |first| first.to_uppercase().collect::<String>() + chars.as_str())
+    };
+    let last = name.char_indices().last().map_or(0, |(at, _)| at);
+    let (prefix, key) = match name[..last].rfind('-') {
+        Some(at) => (&name[..=at], &name[at + 1..]),
+        None => ("", name)
+    };
+    let prefix = prefix.split_inclusive('-').map(capital).collect::<String>();
+    if key.chars().count() > 1 { prefix + &capital(key) } else { prefix + key }
+}
+
+// A character typed as it is, which a key script can hold without a name
+fn plain_char(key: &KeyEvent) -> Option<char> {
+    match (key.code, key.modifiers) {
+        (KeyCode::Char('<'), _) => None,
+        (KeyCode::Char(ch), KeyModifiers::NONE | KeyModifiers::SHIFT) => Some(ch),
+        _ => None
+    }
+}
+
+// Function to write keys as a script like "abc<enter><ctrl-s>", where the keys
+// which aren't plain characters go between < and >
+// Keys without a name are left out
+pub fn keys_to_script(keys: &[KeyEvent]) -> String {
+    let mut script = String::new();
+    for key in keys {
+        match plain_char(key) {
+            Some(ch) => script.push(ch),
+            None => if let Some(name) = key_name(key) {
+                script.push_str(&format!("<{}>", name));
+            }
+        }
+    }
+    script
+}
+
+// Function to read a script written by keys_to_script()
+pub fn parse_script(script: &str) -> Result<Vec<KeyEvent>, String> {
+    let mut keys = Vec::new();
+    let mut rest = script;
+    while let Some(ch) = rest.chars().next() {
+        if ch == '<' {
+            let end = rest.find('>').ok_or_else(|| format!("missing '>' after '{}'", rest))?;
+            let name = &rest[1..end];
+            keys.push(parse_key(name).ok_or_else(|| format!("unknown key '{}'", name))?);
+            rest = &rest[end + 1..];
+        } else {
+            keys.push(KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE));
+            rest = &rest[ch.len_utf8()..];
+        }
+    }
+    Ok(keys)
+}
@@ -3,12 +3,29 @@
 pub type EditorResult<T, E> = std::result::Result<T, E>;
 
 pub enum ResultCode {
-    KeyReadFail
+    KeyReadFail,
+    // Scripted input has no more events
+    InputEnded
 }
 
-#[derive(Default, Copy, Clone)]  // Gives default values to the argument
+#[derive(Default, Copy, Clone, PartialEq)]  // Gives default values to the argument
 pub struct CursorPos {
     pub x : u16,
     pub y : u16,
 }
 
+
+// Part of the screen, like the one taken by a window
+#[derive(Default, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x : u16,
+    pub y : u16,
+    pub width : u16,
+    pub height : u16,
+}
+
+impl Rect {
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
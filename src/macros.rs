@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crossterm::event::KeyEvent;
+use toml::{Table, Value};
+
+use crate::keymap::*;
+
+// Registers are named by a letter or a digit
+pub fn is_register(ch: char) -> bool {
+    ch.is_ascii_alphanumeric()
+}
+
+// Keys recorded into registers, to be played back
+#[derive(Default)]
+pub struct Macros {
+    registers: BTreeMap<char, Vec<KeyEvent>>,
+    // Register the keys being recorded go into
+    pub recording: Option<char>,
+    // Register played last, which gets played again without a name
+    pub last: Option<char>
+}
+
+impl Macros {
+    pub fn get(&self, register: char) -> Option<&[KeyEvent]> {
+        self.registers.get(&register).map(Vec::as_slice)
+    }
+
+    // Function to keep keys in a register, no keys empty it
+    pub fn set(&mut self, register: char, keys: Vec<KeyEvent>) {
+        if keys.is_empty() {
+            self.registers.remove(&register);
+        } else {
+            self.registers.insert(register, keys);
+        }
+    }
+
+    // Function to write every register to a file, as a table of key scripts
+    // like a = "<home>// <down>", returns how many were written
+    pub fn save(&self, path: &Path) -> Result<usize, String> {
+        let table = self.registers
+            .iter()
+            .map(|(register, keys)| (register.to_string(), Value::String(keys_to_script(keys))))
+            .collect::<Table>();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+        }
+        std::fs::write(path, table.to_string()).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(table.len())
+    }
+
+    // Function to read registers from a file written by save(), over the ones
+    // with the same names, returns how many were read
+    pub fn load(&mut self, path: &Path) -> Result<usize, String> {
+        let name = path.display();
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", name, err))?;
+        let table = text.parse::<Table>().map_err(|err| format!("{}: {}", name, err.message()))?;
+
+        // Nothing is taken from a file with a broken macro
+        let mut registers = Vec::new();
+        for (register, script) in &table {
+            let ch = match register.chars().collect::<Vec<char>>()[..] {
+                [ch] if is_register(ch) => ch,
+                _ => return Err(format!("{}: '{}' is not a register", name, register))
+            };
+            let script = script.as_str().ok_or_else(|| format!("{}: {} must be a string", name, register))?;
+            let keys = parse_script(script).map_err(|err| format!("{}: {}: {}", name, register, err))?;
+            registers.push((ch, keys));
+        }
+
+        let count = registers.len();
+        for (register, keys) in registers {
+            self.set(register, keys);
+        }
+        Ok(count)
+    }
+}
@@ -1,18 +1,32 @@
 use crossterm::Result;
 
+mod backend;
 mod screen;
 mod keyboard;
 mod row;
+mod syntax;
+mod history;
+mod fileio;
+mod buffer;
+mod window;
+mod search;
+mod goto;
+mod ex;
+mod config;
+mod keymap;
+mod macros;
+mod vi;
+mod clipboard;
 
 mod editor;
 use editor::*;
 
 fn main() -> Result<()> { 
-    let mut args = std::env::args();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
 
-    // Condition to open file if passed or else open editor
-    let mut editor = if args.len() >= 2 {
-        Editor::open_file(args.nth(1).unwrap())?
+    // Condition to open the files if passed or else open editor
+    let mut editor = if !args.is_empty() {
+        Editor::open_files(&args)?
     } else {
         Editor::new()?
     };
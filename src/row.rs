@@ -1,99 +1,224 @@
-const KILO_TAB_STOP: usize = 8;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::syntax::*;
+
+// Columns between tab stops, set from the config
+static KILO_TAB_STOP: AtomicUsize = AtomicUsize::new(8);
+
+pub fn set_tab_stop(width: usize) {
+    KILO_TAB_STOP.store(width.max(1), Ordering::Relaxed);
+}
+
+pub fn tab_stop() -> usize {
+    KILO_TAB_STOP.load(Ordering::Relaxed)
+}
+
+// A row of text. Positions passed in and out of Row (like cursor.x) count
+// grapheme clusters, so that "é" or "👍" is one step for the cursor, while
+// render positions count terminal columns.
 pub struct Row {
     pub characters: String,
-    pub render: String
+    pub render: String,
+    // Highlight of each grapheme of render
+    pub hl: Vec<Highlight>,
+    // True if a multi-line comment is still open at the end of the row
+    pub hl_open_comment: bool
 }
 
 impl Row {
 
     pub fn new(characters: String) -> Self {
         let render = Row::render_row(&characters);
-        Self{characters, render}
+        Self{characters, render, hl: Vec::new(), hl_open_comment: false}
+    }
+
+    // Function to highlight the render, given whether a multi-line comment
+    // is open from the row above. Returns true if that changed whether a
+    // comment is open at the end of this row, so the next row needs an update too.
+    pub fn update_highlight(&mut self, syntax: Option<&Syntax>, in_comment: bool) -> bool {
+        let graphemes = self.render.graphemes(true).collect::<Vec<&str>>();
+        let (hl, open_comment) = match syntax {
+            Some(syntax) => highlight(&graphemes, syntax, in_comment),
+            None => (vec![Highlight::Normal; graphemes.len()], false)
+        };
+
+        let changed = open_comment != self.hl_open_comment;
+        self.hl = hl;
+        self.hl_open_comment = open_comment;
+        changed
     }
 
     // Function to render tabs as multiple spaces and store it 
     pub fn render_row(characters: &str) -> String {
         let mut render = String::new();
         let mut idx = 0;
+        let tab_stop = tab_stop();
 
-        for c in characters.chars(){
-            match c {
-                '\t' => {
+        for g in characters.graphemes(true){
+            match g {
+                "\t" => {
                     render.push(' ');
                     idx += 1;
-                    while idx % KILO_TAB_STOP != 0 {
+                    while idx % tab_stop != 0 {
                         render.push(' ');
                         idx += 1;
                     }
                 },
-                _ => {
-                    render.push(c);
+                // Control characters (like NUL in a binary file) would
+                // mess up the terminal, so they are shown as '?'
+                _ if is_control(g) => {
+                    render.push('?');
                     idx += 1;
+                },
+                _ => {
+                    render.push_str(g);
+                    idx += g.width();
                 }
             }
         }
         render
     }
 
+    // Number of terminal columns the rendered row takes
     pub fn render_length(&self) -> usize {
-        self.render.len()
+        self.render.width()
     }
 
+    // Function to split the render into screen lines of at most width columns, as ranges
+    // of render columns. Lines after the first are narrower by indent, which is where the
+    // wrap indicator goes. With words a line breaks after a blank when there is one,
+    // so that words aren't cut in two. There is always at least one line.
+    pub fn wrap(&self, width: usize, indent: usize, words: bool) -> Vec<(usize, usize)> {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        let mut col = 0;
+        let mut limit = width.max(1);
+        // Column after the last blank of the current line
+        let mut blank = None;
+
+        for g in self.render.graphemes(true) {
+            let w = g.width();
+            while col + w - start > limit && col > start {
+                let end = match blank {
+                    Some(at) if words && at > start => at,
+                    _ => col
+                };
+                lines.push((start, end));
+                start = end;
+                limit = width.saturating_sub(indent).max(1);
+                blank = None;
+            }
+            if g == " " {
+                blank = Some(col + w);
+            }
+            col += w;
+        }
+        lines.push((start, col));
+        lines
+    }
+
+    // Number of grapheme clusters in the row
     pub fn len(&self) -> usize {
-        self.characters.len()
+        self.characters.graphemes(true).count()
     }
 
-    pub fn cursorx_to_renderx(&self, cx: u16) -> u16 {
+    // Converts a grapheme index into a byte index of characters
+    pub fn byte_index(&self, at: usize) -> usize {
+        self.characters
+            .grapheme_indices(true)
+            .nth(at)
+            .map_or(self.characters.len(), |(idx, _)| idx)
+    }
+
+    // Converts a byte index of characters into a grapheme index
+    pub fn grapheme_index(&self, byte: usize) -> usize {
+        self.characters
+            .grapheme_indices(true)
+            .take_while(|(idx, _)| *idx < byte)
+            .count()
+    }
+
+    // Converts a byte index of characters into an index of the graphemes of render,
+    // which is how hl is indexed
+    pub fn render_index(&self, byte: usize) -> usize {
         let mut rx = 0;
+        let mut idx = 0;
 
-        for c in self.characters.chars().take(cx as usize) {
-            if c == '\t' {
-                rx += (KILO_TAB_STOP - 1) - (rx % KILO_TAB_STOP);
+        for (at, g) in self.characters.grapheme_indices(true) {
+            if at >= byte {
+                break;
+            }
+            if g == "\t" {
+                // Every column of a tab is a space of its own
+                let spaces = tab_stop() - rx % tab_stop();
+                rx += spaces;
+                idx += spaces;
+            } else {
+                rx += if is_control(g) { 1 } else { g.width() };
+                idx += 1;
+            }
+        }
+        idx
+    }
+
+    pub fn cursorx_to_renderx(&self, cx: u16) -> u16 {
+        let mut rx = 0;
+        let tab_stop = tab_stop();
+
+        for g in self.characters.graphemes(true).take(cx as usize) {
+            if g == "\t" {
+                rx += (tab_stop - 1) - (rx % tab_stop) + 1;
+            } else if is_control(g) {
+                rx += 1;
+            } else {
+                rx += g.width();
             }
-            rx += 1;
         }
         rx as u16
     }
 
-    /*
+    // Converts a render column into the index of the grapheme shown there,
+    // the length of the row past its end
     pub fn renderx_to_cursorx(&self, rx: usize) -> u16 {
         let mut current_rx = 0;
-
-        for (cx, c) in self.characters.chars().enumerate() {
-            if c == '\t' {
-                current_rx += (KILO_TAB_STOP - 1) - (current_rx % KILO_TAB_STOP);
+        let tab_stop = tab_stop();
+
+        for (cx, g) in self.characters.graphemes(true).enumerate() {
+            if g == "\t" {
+                current_rx += (tab_stop - 1) - (current_rx % tab_stop) + 1;
+            } else if is_control(g) {
+                current_rx += 1;
+            } else {
+                current_rx += g.width();
             }
-            current_rx += 1;
 
             if current_rx > rx {
                 return cx as u16;
             }
         }
-        self.characters.len() as u16
-    }
-    */
-
-    // Function to insert a character at any position
-    pub fn row_insert_char(&mut self, at: usize, c: char) {
-        if at >= self.characters.len() {
-            self.characters.push(c); }
-        else {
-            self.characters.insert(at, c);
-        }
-        self.render = Row::render_row(&self.characters);
+        self.len() as u16
     }
 
-    // Returns true if a character is deleted else false
-    pub fn del_char(&mut self, at: usize) -> bool {
-        if at >= self.characters.len() {
-            false
-        }
-        else {
-            self.characters.remove(at);
-            self.render = Row::render_row(&self.characters);
-            true
-        }
+    // Graphemes around the one at the given index which are of the same kind
+    // (word characters, blanks or punctuation), as a range of grapheme indexes
+    pub fn word_at(&self, at: usize) -> (usize, usize) {
+        let kind = |g: &str| match g.chars().next() {
+            Some(ch) if ch.is_whitespace() => 0,
+            Some(ch) if ch.is_alphanumeric() || ch == '_' => 1,
+            _ => 2
+        };
+        let graphemes: Vec<&str> = self.characters.graphemes(true).collect();
+        let Some(g) = graphemes.get(at) else {
+            return (at, at);
+        };
+
+        let kind_at = kind(g);
+        let start = graphemes[..at].iter().rev().take_while(|g| kind(g) == kind_at).count();
+        let end = graphemes[at..].iter().take_while(|g| kind(g) == kind_at).count();
+        (at - start, at + end)
     }
 
     pub fn append_string(&mut self, s: &str) {
@@ -101,7 +226,7 @@ impl Row {
         self.render = Row::render_row(&self.characters);
     }
     
-    // Function to split the text of a row, where the cursor is, like when we press Enter
+    // Function to split the text of a row at a byte index, like when we press Enter
     pub fn rowsplit(&mut self, from: usize) -> String {
         // split_off -> returns [from, len) and updates self to [0, from)
         let next_row = self.characters.split_off(from);
@@ -112,4 +237,6 @@ impl Row {
 
 }
 
-
+fn is_control(g: &str) -> bool {
+    g.chars().next().is_some_and(char::is_control)
+}
@@ -1,174 +1,524 @@
-use std::io::{stdout, Write, Stdout};
 
 use crossterm::{
     QueueableCommand, 
-    style::{Print, Color, Colors, SetColors, ResetColor, SetForegroundColor},
+    style::{Print, Color, ResetColor, SetForegroundColor, SetBackgroundColor, SetAttribute, Attribute},
     terminal,
     cursor,
+    event,
     Result};
 
+use crate::backend::*;
 use crate::row::*;
+use crate::syntax::*;
+use crate::search::*;
+use crate::config::*;
+use crate::history::*;
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use kilo_ed_rust::*;
 
+// How a cell is drawn
+#[derive(Copy, Clone, PartialEq)]
+struct Style {
+    fg: Color,
+    bg: Color,
+    reverse: bool
+}
+
+const PLAIN: Style = Style { fg: Color::Reset, bg: Color::Reset, reverse: false };
+
+// One column of the terminal
+#[derive(Clone, PartialEq)]
+struct Cell {
+    // A grapheme, or empty for the column taken by the right half of a wide one
+    text: String,
+    style: Style
+}
+
+impl Cell {
+    fn blank() -> Self {
+        Cell { text: String::from(" "), style: PLAIN }
+    }
+}
+
+// Frames are drawn into cells first, and only the cells which differ from
+// what is on the terminal already get written out, all in one go
 pub struct Screen {
     height : u16,
     width : u16,
-    stdout : Stdout,
+    output : Box<dyn Output>,
+    theme : Theme,
+    // The frame being drawn
+    back : Vec<Cell>,
+    // What the terminal shows, empty when that is unknown
+    front : Vec<Cell>,
+    // Where the cursor goes once the frame is out
+    cursor : (u16, u16)
+}
+
+// What a window shows, for draw_tildes()
+#[derive(Copy, Clone)]
+pub struct View<'a> {
+    pub rows: &'a [Row],
+    pub rowoff: u16,
+    pub coloff: u16,
+    // Columns taken by line numbers, 0 when they are off
+    pub gutter: u16,
+    pub numbers: LineNumbers,
+    // Row of the cursor, its number stands out
+    pub cursor_row: usize,
+    pub matches: &'a [Match],
+    pub current_match: Option<usize>,
+    // Shown in reverse video, the end excluded
+    pub selection: Option<(TextPos, TextPos)>,
+    // With soft wrap, coloff is the first screen line of the row at rowoff which is shown
+    pub wrap: Option<Wrap<'a>>
+}
+
+// How rows are wrapped in a text area
+#[derive(Copy, Clone)]
+pub struct Wrap<'a> {
+    pub width: u16,
+    pub words: bool,
+    pub indicator: &'a str
+}
+
+impl Wrap<'_> {
+    // Columns the wrap indicator takes, none if nothing else would fit
+    pub fn indent(&self) -> usize {
+        let indent = self.indicator.width();
+        if indent < self.width as usize { indent } else { 0 }
+    }
+
+    // The screen lines of a row, as ranges of render columns
+    pub fn lines(&self, row: &Row) -> Vec<(usize, usize)> {
+        row.wrap(self.width as usize, self.indent(), self.words)
+    }
 }
 
+// Smallest terminal the editor draws in, anything smaller only gets a message
+const MIN_WIDTH: u16 = 20;
+const MIN_HEIGHT: u16 = 3;
+
 impl Screen {
-    pub fn new() -> Result<Self> {
-        let (columns, rows) = crossterm::terminal::size()?;
-        Ok(Self {
-            width : columns,
-            height : rows - 2, // So that we can have status bar
-            stdout : stdout()
-        })
+    pub fn new(theme: Theme, output: Box<dyn Output>) -> Result<Self> {
+        let (columns, rows) = output.size()?;
+        let mut screen = Self {
+            width : 0,
+            height : 0,
+            output,
+            theme,
+            back : Vec::new(),
+            front : Vec::new(),
+            cursor : (0, 0)
+        };
+        screen.resize(columns, rows);
+        Ok(screen)
+    }
+
+    // Function to take the new size of the terminal
+    pub fn resize(&mut self, columns: u16, rows: u16) {
+        self.width = columns;
+        self.height = rows.saturating_sub(1); // So that we can have message bar, the status bars are in the windows
+        self.back = vec![Cell::blank(); columns as usize * rows as usize];
+        self.front.clear();
+    }
+
+    pub fn too_small(&self) -> bool {
+        self.width < MIN_WIDTH || self.height + 1 < MIN_HEIGHT
+    }
+
+    // Function to tell that the terminal is too small, in place of everything else
+    pub fn draw_too_small(&mut self) -> Result<()> {
+        let msg = format!("Terminal too small, need {}x{}", MIN_WIDTH, MIN_HEIGHT);
+        self.front.clear();
+        let mut out: Vec<u8> = Vec::new();
+        out.queue(terminal::Clear(terminal::ClearType::All))?
+            .queue(cursor::MoveTo(0, 0))?
+            .queue(Print(msg.chars().take(self.width as usize).collect::<String>()))?;
+        self.output.write(&out)
     }
     
     // Function to clear the screen and move the cursor to top left
     // Can check changes.rs
     pub fn clear(&mut self) -> Result<()>{
+        self.front.clear();
+        let mut out: Vec<u8> = Vec::new();
+        out.queue(terminal::Clear(terminal::ClearType::All))?
+            .queue(cursor::MoveTo(0,0))?; // 1st(column, row)
+        self.output.write(&out)
+    }
+
+    // Function to start drawing a new frame on blank cells
+    pub fn begin_frame(&mut self) {
+        self.back.fill(Cell::blank());
+    }
+
+    // Function to write the cells of the frame which changed since the last one,
+    // with the cursor hidden meanwhile so that it doesn't flicker across the screen
+    pub fn present(&mut self) -> Result<()> {
+        let mut out: Vec<u8> = Vec::new();
+        out.queue(cursor::Hide)?;
+
+        // Nothing is known about the terminal, like after a resize
+        let full = self.front.len() != self.back.len();
+        if full {
+            out.queue(ResetColor)?
+                .queue(SetAttribute(Attribute::NoReverse))?
+                .queue(terminal::Clear(terminal::ClearType::All))?;
+        }
+
+        let width = self.width as usize;
+        let mut style = PLAIN;
+        // Where the terminal cursor is after the last cell written
+        let mut at = None;
+        for (idx, cell) in self.back.iter().enumerate() {
+            let unchanged = if full { *cell == Cell::blank() } else { self.front[idx] == *cell };
+            // The right half of a wide grapheme comes with its left half
+            if unchanged || cell.text.is_empty() {
+                continue;
+            }
+
+            let (x, y) = ((idx % width) as u16, (idx / width) as u16);
+            if at != Some((x, y)) {
+                out.queue(cursor::MoveTo(x, y))?;
+            }
+            if cell.style.fg != style.fg {
+                out.queue(SetForegroundColor(cell.style.fg))?;
+            }
+            if cell.style.bg != style.bg {
+                out.queue(SetBackgroundColor(cell.style.bg))?;
+            }
+            if cell.style.reverse != style.reverse {
+                out.queue(SetAttribute(if cell.style.reverse { Attribute::Reverse } else { Attribute::NoReverse }))?;
+            }
+            style = cell.style;
+            out.queue(Print(&cell.text))?;
+
+            // Going past the end of a line the terminal may wrap or not, so moving there again
+            let next = x as usize + cell.text.width().max(1);
+            at = if next < width { Some((next as u16, y)) } else { None };
+        }
+
+        if style != PLAIN {
+            out.queue(ResetColor)?.queue(SetAttribute(Attribute::NoReverse))?;
+        }
+        out.queue(cursor::MoveTo(self.cursor.0, self.cursor.1))?
+            .queue(cursor::Show)?;
+
+        self.output.write(&out)?;
+        self.front.clone_from(&self.back);
+        Ok(())
+    }
+
+    // Function to put text into the cells of a row from a column on, up to the
+    // given column, returns the column after it
+    fn put(&mut self, x: u16, y: u16, text: &str, style: Style, end: u16) -> u16 {
+        let mut col = x;
+        for g in text.graphemes(true) {
+            let w = g.width() as u16;
+            // A combining mark on its own goes with the grapheme before it
+            if w == 0 {
+                if col > x {
+                    let idx = self.index(col - 1, y);
+                    self.back[idx].text.push_str(g);
+                }
+                continue;
+            }
+            if col + w > end.min(self.width) {
+                break;
+            }
+            self.set(col, y, Cell { text: g.to_string(), style });
+            for right in col + 1..col + w {
+                self.set(right, y, Cell { text: String::new(), style });
+            }
+            col += w;
+        }
+        col
+    }
+
+    // Function to set one cell, without leaving half of a wide grapheme behind
+    fn set(&mut self, x: u16, y: u16, cell: Cell) {
+        if y > self.height {
+            return;
+        }
+        let idx = self.index(x, y);
+        let old = &self.back[idx].text;
+        let (right_half, wide) = (old.is_empty(), old.width() > 1);
+        if right_half && x > 0 && !cell.text.is_empty() {
+            self.back[idx - 1] = Cell::blank();
+        }
+        if wide && x + 1 < self.width {
+            self.back[idx + 1] = Cell::blank();
+        }
+        self.back[idx] = cell;
+    }
+
+    fn index(&self, x: u16, y: u16) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+
+    // The text on the screen line by line, as the output shows it after the frames written
+    #[cfg(test)]
+    pub fn lines(&self) -> Vec<String> {
+        self.output.lines()
+    }
+
+    // Where the output has the cursor after the last frame, as (column, row)
+    #[cfg(test)]
+    pub fn cursor(&self) -> (u16, u16) {
+        self.output.cursor()
+    }
 
-        self.stdout
-                .queue(terminal::Clear(terminal::ClearType::All))?
-                .queue(cursor::MoveTo(0,0))? // 1st(column, row)
-                .flush()
+    // Area of the screen shared by the windows
+    pub fn area(&self) -> Rect {
+        Rect {
+            x : 0,
+            y : 0,
+            width : self.width,
+            height : self.height
+        }
     }
 
     // Function to draw Tildes(~) on the screen
-    // Alongwith welcome msg and rows
+    // Alongwith welcome msg and rows, inside the text area of a window
     // Can check changes.rs
-    pub fn draw_tildes(&mut self, erows: &[Row], rowoff: u16, coloff: u16) -> Result<()>{
-        for row in 0..self.height {
+    // Search matches get a background, the current one a brighter one
+    pub fn draw_tildes(&mut self, area: &Rect, view: &View) -> Result<()>{
+        let View { rows: erows, rowoff, coloff, gutter, numbers, cursor_row, wrap, .. } = *view;
+
+        // Line numbers take the left of the area and the text the rest
+        let gutter = if gutter < area.width { gutter } else { 0 };
+        let text = Rect { x: area.x + gutter, width: area.width - gutter, ..*area };
+        let right = text.x + text.width;
+
+        // What goes on each screen line: a row, which of its screen lines it is
+        // and the render columns shown there
+        let wrap = wrap.map(|wrap| Wrap { width: text.width, ..wrap });
+        let mut lines = Vec::with_capacity(area.height as usize);
+        match wrap {
+            None => lines.extend((0..area.height as usize).map(|row| {
+                (row + rowoff as usize, 0, (coloff as usize, (coloff + text.width) as usize))
+            })),
+            Some(wrap) => {
+                let mut filerow = rowoff as usize;
+                let mut skip = coloff as usize;
+                while lines.len() < area.height as usize {
+                    match erows.get(filerow) {
+                        Some(row) => lines.extend(wrap.lines(row)
+                            .into_iter()
+                            .enumerate()
+                            .skip(skip)
+                            .map(|(part, range)| (filerow, part, range))),
+                        None => lines.push((filerow, 0, (0, 0)))
+                    }
+                    filerow += 1;
+                    skip = 0;
+                }
+                lines.truncate(area.height as usize);
+            }
+        }
+
+        for (row, &(filerow, part, range)) in lines.iter().enumerate() {
+            let screen_row = area.y + row as u16;
+            if gutter > 0 && filerow < erows.len() && part == 0 {
+                let style = if filerow == cursor_row { Style { fg: self.theme.current_line_number, ..PLAIN } }
+                            else { Style { fg: self.theme.line_number, ..PLAIN } };
+                let number = if numbers == LineNumbers::Relative && filerow != cursor_row {
+                    filerow.abs_diff(cursor_row) }
+                else {
+                    filerow + 1 };
+                let number = format!("{:>1$} ", number, gutter as usize - 1);
+                self.put(area.x, screen_row, &number, style, text.x);
+            }
+
             const VERSION: &str = env!("CARGO_PKG_VERSION");
-            let filerow = (row + rowoff) as usize;
             if filerow >= erows.len() {
                 // Welcome msg along with tilde
-                if erows.len() == 0 && row == self.height/3 {
+                if erows.is_empty() && row == text.height as usize / 3 {
                     let mut welcome = format!("Kilo Editor -- version {VERSION}");
-                    welcome.truncate(self.width as usize);
+                    welcome.truncate(text.width as usize);
 
                     // Centering welcome msg with tildes
-                    if welcome.len() < self.width as usize {
-                        let leftmost = (self.width - welcome.len() as u16)/2;
-                        self.stdout
-                            .queue(cursor::MoveTo(0,row))?
-                            .queue(Print("~".to_string()))?
-                            .queue(cursor::MoveTo(leftmost,row))?
-                            .queue(Print(welcome))?;
+                    if welcome.len() < text.width as usize {
+                        let leftmost = (text.width - welcome.len() as u16)/2;
+                        self.put(text.x, screen_row, "~", PLAIN, right);
+                        self.put(text.x + leftmost, screen_row, &welcome, PLAIN, right);
                     }
                     else {
-                        self.stdout
-                            .queue(cursor::MoveTo(0,row))?
-                            .queue(Print(welcome))?;
+                        self.put(text.x, screen_row, &welcome, PLAIN, right);
                     }
                 }
 
                 // Tildes on all lines
-                else {
-                    self.stdout
-                        .queue(cursor::MoveTo(0,row))?
-                        .queue(Print("~".to_string()))?;
-                    /* For Step 40 - check it 
-                       .queue(terminal::Clear(terminal::ClearType::UntilNewLine))?;
-                       */
-                    //println!("~\r");
+                else if text.width > 0 {
+                    self.put(text.x, screen_row, "~", PLAIN, right);
                 }
             }
 
             // Printing the row
             else {
-                let mut len = erows[filerow].render_length();
-                if len < coloff as usize {
-                    continue; }
-                len -= coloff as usize;
-                let start = coloff as usize;
-                let end = start 
-                    + if len > self.width as usize {
-                            self.width as usize }
-                       else {
-                            len };
-                
-                self.stdout.queue(cursor::MoveTo(0,row))?;
-
-                for ch in erows[filerow].render[start..end].to_string().chars() {
-                    if ch.is_digit(10) {
-                        self.stdout
-                            .queue(SetForegroundColor(Color::Red))?
-                            .queue(Print(ch))?
-                            .queue(SetForegroundColor(Color::Reset))?;
-                    } else {
-                        self.stdout
-                            .queue(Print(ch))?;
-                    }
+                let mut x = text.x;
+                if let Some(wrap) = wrap.filter(|_| part > 0) {
+                    let style = Style { fg: self.theme.line_number, ..PLAIN };
+                    x += wrap.indent() as u16;
+                    self.put(text.x, screen_row, wrap.indicator, style, x);
                 }
+                self.draw_row(x, screen_row, right, view, filerow, range);
             }
         }
-        
-       self.stdout.flush()
-//       Ok(())
-    }
-
-    // Function to move the cursor to desired position
-    pub fn move_to(
-        &mut self,
-        position: &CursorPos,
-        render_x: u16,
-        rowoff: u16,
-        coloff: u16
-        ) -> Result<()> {
-        self.stdout.queue(cursor::MoveTo(render_x - coloff, position.y - rowoff))?;
         Ok(())
     }
 
-    // Function to know the height and width of the window
-    pub fn bounds(&self)  -> CursorPos {
-        CursorPos {
-            x : self.width,
-            y : self.height
+    // Function to draw the render columns [start, end) of a row from x on
+    fn draw_row(&mut self, x: u16, y: u16, right: u16, view: &View, filerow: usize, (start, end): (usize, usize)) {
+        let View { rows: erows, matches, current_match, selection, .. } = *view;
+        let row = &erows[filerow];
+        if row.render_length() < start {
+            return;
+        }
+        let end = end.min(start + (right - x) as usize);
+
+        // Matches on this row as ranges of render graphemes, the matches are sorted by row
+        let first = matches.partition_point(|m| m.row < filerow);
+        let row_matches = matches[first..]
+            .iter()
+            .zip(first..)
+            .take_while(|(m, _)| m.row == filerow)
+            .map(|(m, idx)| {
+                let range = row.render_index(m.start)..row.render_index(m.end);
+                let color = if Some(idx) == current_match { self.theme.current_match }
+                            else { self.theme.search_match };
+                (range, color)
+            })
+            .collect::<Vec<_>>();
+        let selected = selection
+            .filter(|(start, end)| (start.row..=end.row).contains(&filerow))
+            .map(|(start, end)| {
+                let from = if filerow == start.row { row.render_index(start.byte) } else { 0 };
+                let to = if filerow == end.row { row.render_index(end.byte) } else { usize::MAX };
+                from..to
+            });
+
+        // Walking the render by terminal columns, as a grapheme can take
+        // two columns (CJK, emoji) and a combining mark takes none
+        let mut col = 0;
+        for (idx, g) in row.render.graphemes(true).enumerate() {
+            let next = col + g.width();
+            if next > end {
+                break;
+            }
+            let at = x + (col.max(start) - start) as u16;
+            if col < start {
+                // Wide character cut by the left edge of the screen
+                if next > start {
+                    self.put(at, y, &" ".repeat(next - start), PLAIN, right);
+                }
+            } else {
+                let hl = row.hl.get(idx).copied().unwrap_or(Highlight::Normal);
+                let bg = row_matches
+                    .iter()
+                    .find(|(range, _)| range.contains(&idx))
+                    .map_or(Color::Reset, |(_, color)| *color);
+                let style = Style {
+                    fg: self.syntax_to_color(hl),
+                    bg,
+                    reverse: selected.as_ref().is_some_and(|range| range.contains(&idx))
+                };
+                self.put(at, y, g, style, right);
+            }
+            col = next;
         }
     }
 
+    // Function to move the cursor to desired position
+    // Positions are relative to the text area of the window
+    pub fn move_to(&mut self, area: &Rect, x: u16, y: u16) -> Result<()> {
+        self.cursor = (area.x + x.min(area.width.saturating_sub(1)), area.y + y);
+        Ok(())
+    }
+
+    // Function to draw the status bar of a window, just below its text area
     pub fn draw_status_bar<T: Into<String>>(
         &mut self, 
+        area: &Rect,
         left: T, 
         right: T, 
-        msg: String) -> Result<()> {
+        active: bool) -> Result<()> {
 
         let left = left.into();
         let right = right.into();
 
-        let left_width = left.len();
-        let right_width = right.len();
-        let screen_width = self.width as usize;
-
-        let lstatus = format!("{left:0$}",left_width.min(screen_width));
-        let mut rstatus = String::new();
-        let mut len = lstatus.len();
-        
-        while len < screen_width {
-            if screen_width - len == right_width {
-                rstatus.push_str(right.as_str());
-                break;
-            }
-            else {
-                rstatus.push(' ');
-                len += 1;
-            }
+        let right_width = right.width();
+        let screen_width = area.width as usize;
+
+        // Cut by graphemes, a wide one which doesn't fit whole is left out
+        let mut len = 0;
+        let lstatus = left.graphemes(true)
+            .take_while(|g| {
+                len += g.width();
+                len <= screen_width
+            })
+            .collect::<String>();
+        let len = lstatus.width();
+
+        // The right part only goes in whole, when there is room for it
+        let rstatus = if screen_width - len >= right_width {
+            format!("{}{}", " ".repeat(screen_width - len - right_width), right)
+        } else {
+            " ".repeat(screen_width - len)
+        };
+
+        // The window with the focus stands out
+        let style = if active { Style { fg: self.theme.status_fg, bg: self.theme.status_bg, reverse: false } }
+                    else { Style { fg: self.theme.status_fg, bg: self.theme.inactive_status_bg, reverse: false } };
+
+        self.put(area.x, area.y + area.height, &format!("{lstatus}{rstatus}"), style, area.x + area.width);
+        Ok(())
+    }
+
+    // Function to draw the line between two windows side by side
+    pub fn draw_separator(&mut self, area: &Rect) -> Result<()> {
+        let style = Style { fg: self.theme.status_fg, bg: self.theme.inactive_status_bg, reverse: false };
+        for row in 0..area.height {
+            self.put(area.x, area.y + row, "|", style, area.x + area.width);
+        }
+        Ok(())
+    }
+
+    // Function to have the terminal report mouse clicks and scrolling, or stop it
+    pub fn capture_mouse(&mut self, on: bool) -> Result<()> {
+        let mut out: Vec<u8> = Vec::new();
+        if on {
+            out.queue(event::EnableMouseCapture)?;
+        } else {
+            out.queue(event::DisableMouseCapture)?;
         }
-        
-        self.stdout
-            .queue(cursor::MoveTo(0, self.height))?
-            .queue(SetColors(Colors::new(Color::White, Color::DarkMagenta)))?
-            .queue(Print(format!("{lstatus}{rstatus}")))?
-            .queue(ResetColor)?
-            .queue(cursor::MoveTo(0, self.height + 1))?
-            .queue(Print(format!("{msg}")))?;
+        self.output.write(&out)
+    }
+
+    // Function to hand text (already in base64) to the clipboard of the terminal with OSC 52,
+    // which also works over SSH
+    pub fn set_clipboard(&mut self, encoded: &str) -> Result<()> {
+        self.output.write(format!("\x1b]52;c;{}\x07", encoded).as_bytes())
+    }
 
+    pub fn draw_message_bar(&mut self, msg: String) -> Result<()> {
+        self.put(0, self.height, &msg, PLAIN, self.width);
         Ok(())
     }
 
+    // Colour used on screen for each kind of highlight
+    fn syntax_to_color(&self, hl: Highlight) -> Color {
+        match hl {
+            Highlight::Normal => Color::Reset,
+            Highlight::Comment | Highlight::MlComment => self.theme.comment,
+            Highlight::Keyword1 => self.theme.keyword1,
+            Highlight::Keyword2 => self.theme.keyword2,
+            Highlight::String => self.theme.string,
+            Highlight::Number => self.theme.number
+        }
+    }
 }
-
@@ -0,0 +1,71 @@
+use regex::{Captures, Regex, RegexBuilder};
+
+// A match of the search query, as a byte range of a row
+#[derive(Copy, Clone)]
+pub struct Match {
+    pub row: usize,
+    pub start: usize,
+    pub end: usize
+}
+
+// How the search query is matched, toggled from the search prompt
+#[derive(Default, Copy, Clone)]
+pub struct SearchOptions {
+    pub regex: bool,
+    pub ignore_case: bool,
+    pub whole_word: bool
+}
+
+impl SearchOptions {
+    // Flags shown after the query in the prompt
+    pub fn label(&self) -> String {
+        let flags = [(self.regex, "regex"), (self.ignore_case, "icase"), (self.whole_word, "word")]
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect::<Vec<&str>>();
+
+        if flags.is_empty() { String::new() } else { format!(" [{}]", flags.join(" ")) }
+    }
+
+    // Function to turn the query into a pattern, a plain query is matched literally
+    pub fn compile(&self, query: &str) -> Result<Regex, String> {
+        let mut pattern = if self.regex { query.to_string() } else { regex::escape(query) };
+        if self.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(self.ignore_case)
+            .build()
+            // The last line of the error says what is wrong, the others draw the pattern
+            .map_err(|err| err.to_string().lines().last().unwrap_or_default().trim().to_string())
+    }
+}
+
+// Function to find the first match in the line starting at or after the byte `from`
+// Empty matches (like from "a*") are skipped, they would leave nothing to see
+pub fn next_match<'a>(pattern: &Regex, line: &'a str, mut from: usize) -> Option<Captures<'a>> {
+    while from <= line.len() {
+        let caps = pattern.captures_at(line, from)?;
+        let found = caps.get(0)?;
+        if !found.is_empty() {
+            return Some(caps);
+        }
+        from = found.end() + line[found.end()..].chars().next().map_or(1, char::len_utf8);
+    }
+    None
+}
+
+// Function to find every match in the lines, in order
+pub fn find_all<'a>(pattern: &Regex, lines: impl Iterator<Item = &'a str>) -> Vec<Match> {
+    let mut matches = Vec::new();
+    for (row, line) in lines.enumerate() {
+        let mut from = 0;
+        while let Some(found) = next_match(pattern, line, from).and_then(|caps| caps.get(0)) {
+            matches.push(Match { row, start: found.start(), end: found.end() });
+            from = found.end();
+        }
+    }
+    matches
+}
@@ -0,0 +1,246 @@
+use std::path::Path;
+
+// What a character of the rendered row is, decides its colour on screen
+#[derive(Copy, Clone, PartialEq)]
+pub enum Highlight {
+    Normal,
+    Comment,
+    MlComment,
+    Keyword1,
+    Keyword2,
+    String,
+    Number
+}
+
+// Rules to highlight one filetype
+pub struct Syntax {
+    pub filetype: &'static str,
+    pub extensions: &'static [&'static str],
+    // Keywords1 are statements (if, fn, ...) and Keywords2 types and constants
+    pub keywords1: &'static [&'static str],
+    pub keywords2: &'static [&'static str],
+    pub singleline_comment: &'static str,
+    pub multiline_comment: Option<(&'static str, &'static str)>,
+    pub quotes: &'static str,
+    pub numbers: bool,
+    // Lines starting with '#' are headings, for Markdown
+    pub headings: bool
+}
+
+// Database of known filetypes
+const HLDB: &[Syntax] = &[
+    Syntax {
+        filetype: "rust",
+        extensions: &["rs"],
+        keywords1: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else",
+            "enum", "extern", "fn", "for", "if", "impl", "in", "let", "loop", "match",
+            "mod", "move", "mut", "pub", "ref", "return", "static", "struct", "super",
+            "trait", "type", "unsafe", "use", "where", "while"
+        ],
+        keywords2: &[
+            "bool", "char", "str", "String", "Vec", "Option", "Result", "Box", "Self",
+            "self", "true", "false", "Some", "None", "Ok", "Err", "i8", "i16", "i32",
+            "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize", "f32", "f64"
+        ],
+        singleline_comment: "//",
+        multiline_comment: Some(("/*", "*/")),
+        // No single quotes, they are also used for lifetimes
+        quotes: "\"",
+        numbers: true,
+        headings: false
+    },
+    Syntax {
+        filetype: "c",
+        extensions: &["c", "h", "cpp", "hpp", "cc"],
+        keywords1: &[
+            "switch", "if", "while", "for", "break", "continue", "return", "else",
+            "struct", "union", "typedef", "static", "enum", "class", "case", "default",
+            "do", "goto", "sizeof", "const", "extern", "volatile", "#include", "#define"
+        ],
+        keywords2: &[
+            "int", "long", "double", "float", "char", "unsigned", "signed", "void",
+            "short", "auto", "bool", "NULL", "true", "false"
+        ],
+        singleline_comment: "//",
+        multiline_comment: Some(("/*", "*/")),
+        quotes: "\"'",
+        numbers: true,
+        headings: false
+    },
+    Syntax {
+        filetype: "python",
+        extensions: &["py"],
+        keywords1: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def",
+            "del", "elif", "else", "except", "finally", "for", "from", "global", "if",
+            "import", "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise",
+            "return", "try", "while", "with", "yield"
+        ],
+        keywords2: &[
+            "True", "False", "None", "self", "int", "str", "float", "bool", "list",
+            "dict", "tuple", "set"
+        ],
+        singleline_comment: "#",
+        multiline_comment: None,
+        quotes: "\"'",
+        numbers: true,
+        headings: false
+    },
+    Syntax {
+        filetype: "markdown",
+        extensions: &["md", "markdown"],
+        keywords1: &[],
+        keywords2: &[],
+        singleline_comment: "",
+        multiline_comment: Some(("<!--", "-->")),
+        quotes: "`",
+        numbers: false,
+        headings: true
+    },
+    Syntax {
+        filetype: "toml",
+        extensions: &["toml"],
+        keywords1: &[],
+        keywords2: &["true", "false", "inf", "nan"],
+        singleline_comment: "#",
+        multiline_comment: None,
+        quotes: "\"'",
+        numbers: true,
+        headings: false
+    },
+    Syntax {
+        filetype: "json",
+        extensions: &["json"],
+        keywords1: &[],
+        keywords2: &["true", "false", "null"],
+        singleline_comment: "",
+        multiline_comment: None,
+        quotes: "\"",
+        numbers: true,
+        headings: false
+    }
+];
+
+impl Syntax {
+    // Function to find the syntax rules matching the extension of a file
+    pub fn for_file(filename: &str) -> Option<&'static Syntax> {
+        let ext = Path::new(filename).extension()?.to_str()?;
+        HLDB.iter().find(|syntax| syntax.extensions.contains(&ext))
+    }
+}
+
+fn is_separator(g: &str) -> bool {
+    g.chars().all(|c| c.is_whitespace() || ",.()+-/*=~%<>[];:{}&|!?".contains(c))
+}
+
+// True if the graphemes from idx on spell out pat
+fn starts_with(graphemes: &[&str], idx: usize, pat: &str) -> bool {
+    !pat.is_empty()
+        && idx + pat.len() <= graphemes.len()
+        && graphemes[idx..idx + pat.len()].concat() == pat
+}
+
+// Function to highlight the graphemes of a rendered row.
+// in_comment tells if a multi-line comment is still open from the rows above,
+// and the returned bool if it's still open at the end of this row.
+pub fn highlight(graphemes: &[&str], syntax: &Syntax, in_comment: bool) -> (Vec<Highlight>, bool) {
+    let mut hl = vec![Highlight::Normal; graphemes.len()];
+
+    if syntax.headings && graphemes.first() == Some(&"#") {
+        return (vec![Highlight::Keyword1; graphemes.len()], in_comment);
+    }
+
+    let mut prev_sep = true;
+    let mut in_string: Option<&str> = None;
+    let mut in_comment = in_comment;
+    let mut idx = 0;
+
+    'outer: while idx < graphemes.len() {
+        let g = graphemes[idx];
+        let prev_hl = if idx > 0 { hl[idx - 1] } else { Highlight::Normal };
+
+        if in_string.is_none() && !in_comment && starts_with(graphemes, idx, syntax.singleline_comment) {
+            for h in &mut hl[idx..] {
+                *h = Highlight::Comment;
+            }
+            break;
+        }
+
+        if let Some((start, end)) = syntax.multiline_comment {
+            if in_comment {
+                hl[idx] = Highlight::MlComment;
+                if starts_with(graphemes, idx, end) {
+                    for h in &mut hl[idx..idx + end.len()] {
+                        *h = Highlight::MlComment;
+                    }
+                    idx += end.len();
+                    in_comment = false;
+                    prev_sep = true;
+                } else {
+                    idx += 1;
+                }
+                continue;
+            } else if in_string.is_none() && starts_with(graphemes, idx, start) {
+                for h in &mut hl[idx..idx + start.len()] {
+                    *h = Highlight::MlComment;
+                }
+                idx += start.len();
+                in_comment = true;
+                continue;
+            }
+        }
+
+        if let Some(quote) = in_string {
+            hl[idx] = Highlight::String;
+            // An escaped character doesn't end the string
+            if g == "\\" && idx + 1 < graphemes.len() {
+                hl[idx + 1] = Highlight::String;
+                idx += 2;
+                continue;
+            }
+            if g == quote {
+                in_string = None;
+            }
+            idx += 1;
+            prev_sep = true;
+            continue;
+        } else if g.len() == 1 && syntax.quotes.contains(g) {
+            in_string = Some(g);
+            hl[idx] = Highlight::String;
+            idx += 1;
+            continue;
+        }
+
+        if syntax.numbers
+            && ((g.bytes().all(|b| b.is_ascii_digit()) && (prev_sep || prev_hl == Highlight::Number))
+                || (g == "." && prev_hl == Highlight::Number)) {
+            hl[idx] = Highlight::Number;
+            idx += 1;
+            prev_sep = false;
+            continue;
+        }
+
+        if prev_sep {
+            let keywords = syntax.keywords1.iter().map(|kw| (kw, Highlight::Keyword1))
+                .chain(syntax.keywords2.iter().map(|kw| (kw, Highlight::Keyword2)));
+            for (kw, kind) in keywords {
+                let end = idx + kw.len();
+                if starts_with(graphemes, idx, kw)
+                    && (end == graphemes.len() || is_separator(graphemes[end])) {
+                    for h in &mut hl[idx..end] {
+                        *h = kind;
+                    }
+                    idx = end;
+                    prev_sep = false;
+                    continue 'outer;
+                }
+            }
+        }
+
+        prev_sep = is_separator(g);
+        idx += 1;
+    }
+
+    (hl, in_comment)
+}
@@ -0,0 +1,449 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::row::*;
+use crate::macros::is_register;
+
+use kilo_ed_rust::*;
+
+// Modes of the vi-style editing, without it the editor is always inserting
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual
+}
+
+impl Mode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL"
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordStart,
+    WordBack,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FirstLine,
+    LastLine
+}
+
+impl Motion {
+    // An operator also takes the character the motion lands on
+    pub fn inclusive(&self) -> bool {
+        matches!(self, Motion::WordEnd | Motion::LineEnd)
+    }
+
+    // An operator works on every line from the cursor to where the motion lands
+    pub fn linewise(&self) -> bool {
+        matches!(self, Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operator {
+    Delete,
+    Yank,
+    Change
+}
+
+// Where i, a, I, A, o and O start inserting
+#[derive(Copy, Clone, PartialEq)]
+pub enum InsertAt {
+    Before,
+    After,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Action {
+    Move(Motion),
+    // Without a motion the operator works on whole lines (dd) or on the selection
+    Operate(Operator, Option<Motion>),
+    Insert(InsertAt),
+    Put { before: bool },
+    Undo,
+    Redo,
+    Repeat,
+    Visual,
+    CommandLine,
+    // q and a register, q on its own stops the recording
+    Record(char),
+    // @ and a register, @@ plays the last one again
+    Play(Option<char>)
+}
+
+impl Action {
+    // Actions which change the text, the last one of them is repeated by '.'
+    pub fn is_change(&self) -> bool {
+        matches!(self, Action::Operate(Operator::Delete | Operator::Change, _)
+                     | Action::Insert(_) | Action::Put { .. })
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct ViCommand {
+    pub count: Option<usize>,
+    pub action: Action
+}
+
+pub enum Parse {
+    // More keys are needed, like after "d" or "2"
+    Pending,
+    Invalid,
+    Done(ViCommand)
+}
+
+// State of the vi-style editing
+pub struct Vi {
+    pub enabled: bool,
+    pub mode: Mode,
+    // Keys of the command being typed in normal mode
+    pub pending: String,
+    pub keys: Vec<KeyEvent>,
+    // Keys of the last change for '.', and of the one still going on in insert mode
+    pub last_change: Vec<KeyEvent>,
+    pub recording: Option<Vec<KeyEvent>>,
+    // Where the visual selection started
+    pub anchor: CursorPos,
+    // Buffer and undo steps when a change started inserting, everything
+    // till Esc is joined into one step
+    pub insert_from: Option<(usize, usize)>
+}
+
+impl Vi {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            mode: if enabled { Mode::Normal } else { Mode::Insert },
+            pending: String::new(),
+            keys: Vec::new(),
+            last_change: Vec::new(),
+            recording: None,
+            anchor: CursorPos::default(),
+            insert_from: None
+        }
+    }
+}
+
+// Function to read the keys typed so far in normal or visual mode,
+// like "3w", "d2w", "dd", "gg" or "p"
+pub fn parse(keys: &str, visual: bool) -> Parse {
+    let mut chars = keys.chars().peekable();
+    let first_count = read_count(&mut chars);
+
+    let Some(ch) = chars.next() else {
+        return Parse::Pending;
+    };
+    let action = match ch {
+        'g' => match chars.next() {
+            Some('g') => Action::Move(Motion::FirstLine),
+            Some(_) => return Parse::Invalid,
+            None => return Parse::Pending
+        },
+        'x' if visual => Action::Operate(Operator::Delete, None),
+        'x' => Action::Operate(Operator::Delete, Some(Motion::Right)),
+        'd' | 'y' | 'c' => {
+            let operator = match ch {
+                'd' => Operator::Delete,
+                'y' => Operator::Yank,
+                _ => Operator::Change
+            };
+            if visual {
+                Action::Operate(operator, None)
+            } else {
+                let second_count = read_count(&mut chars);
+                let motion = match chars.next() {
+                    None => return Parse::Pending,
+                    // Doubling the operator works on lines, like dd
+                    Some(next) if next == ch => None,
+                    Some('g') => match chars.next() {
+                        Some('g') => Some(Motion::FirstLine),
+                        Some(_) => return Parse::Invalid,
+                        None => return Parse::Pending
+                    },
+                    Some(next) => match motion(next) {
+                        Some(motion) => Some(motion),
+                        None => return Parse::Invalid
+                    }
+                };
+                let count = match (first_count, second_count) {
+                    (None, None) => None,
+                    (first, second) => Some(first.unwrap_or(1) * second.unwrap_or(1))
+                };
+                return Parse::Done(ViCommand { count, action: Action::Operate(operator, motion) });
+            }
+        },
+        'i' if !visual => Action::Insert(InsertAt::Before),
+        'a' if !visual => Action::Insert(InsertAt::After),
+        'I' if !visual => Action::Insert(InsertAt::LineStart),
+        'A' if !visual => Action::Insert(InsertAt::LineEnd),
+        'o' if !visual => Action::Insert(InsertAt::LineBelow),
+        'O' if !visual => Action::Insert(InsertAt::LineAbove),
+        'p' if !visual => Action::Put { before: false },
+        'P' if !visual => Action::Put { before: true },
+        'u' if !visual => Action::Undo,
+        // Ctrl-r
+        '\x12' if !visual => Action::Redo,
+        '.' if !visual => Action::Repeat,
+        ':' if !visual => Action::CommandLine,
+        'q' if !visual => match chars.next() {
+            Some(register) if is_register(register) => Action::Record(register),
+            Some(_) => return Parse::Invalid,
+            None => return Parse::Pending
+        },
+        '@' if !visual => match chars.next() {
+            Some('@') => Action::Play(None),
+            Some(register) if is_register(register) => Action::Play(Some(register)),
+            Some(_) => return Parse::Invalid,
+            None => return Parse::Pending
+        },
+        'v' => Action::Visual,
+        _ => match motion(ch) {
+            Some(motion) => Action::Move(motion),
+            None => return Parse::Invalid
+        }
+    };
+    Parse::Done(ViCommand { count: first_count, action })
+}
+
+fn motion(ch: char) -> Option<Motion> {
+    Some(match ch {
+        'h' => Motion::Left,
+        'l' => Motion::Right,
+        'k' => Motion::Up,
+        'j' => Motion::Down,
+        'w' => Motion::WordStart,
+        'b' => Motion::WordBack,
+        'e' => Motion::WordEnd,
+        '0' => Motion::LineStart,
+        '$' => Motion::LineEnd,
+        'G' => Motion::LastLine,
+        _ => return None
+    })
+}
+
+// A count doesn't start with 0, that one goes to the start of the line
+fn read_count(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<usize> {
+    let mut count: Option<usize> = None;
+    while let Some(digit) = chars.peek().and_then(|ch| ch.to_digit(10)) {
+        if digit == 0 && count.is_none() {
+            break;
+        }
+        count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+        chars.next();
+    }
+    count
+}
+
+// Function to find where a motion takes the cursor
+pub fn apply_motion(rows: &[Row], pos: CursorPos, motion: Motion, count: Option<usize>) -> CursorPos {
+    let times = count.unwrap_or(1);
+    let last_row = rows.len().saturating_sub(1);
+    let row_len = |y: usize| rows.get(y).map_or(0, |row| row.len());
+
+    let (x, y) = (pos.x as usize, pos.y as usize);
+    let (x, y) = match motion {
+        Motion::Left => (x.saturating_sub(times), y),
+        Motion::Right => (x.saturating_add(times).min(row_len(y)), y),
+        Motion::Up => (x, y.saturating_sub(times)),
+        Motion::Down => (x, y.saturating_add(times).min(last_row)),
+        Motion::LineStart => (0, y),
+        Motion::LineEnd => (row_len(y).saturating_sub(1), y),
+        // gg and G go to the line of the count if there is one
+        Motion::FirstLine => (0, count.map_or(0, |line| line - 1).min(last_row)),
+        Motion::LastLine => (0, count.map_or(last_row, |line| line - 1).min(last_row)),
+        Motion::WordStart | Motion::WordBack | Motion::WordEnd => {
+            let text = Text::new(rows);
+            let mut at = (y, x);
+            for _ in 0..times {
+                at = match motion {
+                    Motion::WordStart => text.word_start(at),
+                    Motion::WordBack => text.word_back(at),
+                    _ => text.word_end(at)
+                };
+            }
+            (at.1, at.0)
+        }
+    };
+    CursorPos { x: x.min(row_len(y)) as u16, y: y as u16 }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Class {
+    Blank,
+    Word,
+    Punct,
+    // An empty line is a word of its own for w and b
+    EmptyLine
+}
+
+// The rows as graphemes, walked one (row, x) at a time where x == len
+// is the line break at the end of a row
+struct Text<'a> {
+    lines: Vec<Vec<&'a str>>
+}
+
+impl<'a> Text<'a> {
+    fn new(rows: &'a [Row]) -> Self {
+        Self { lines: rows.iter().map(|row| row.characters.graphemes(true).collect()).collect() }
+    }
+
+    fn class(&self, (row, x): (usize, usize)) -> Class {
+        let line = &self.lines[row];
+        match line.get(x) {
+            None if line.is_empty() => Class::EmptyLine,
+            None => Class::Blank,
+            Some(g) if g.chars().all(char::is_whitespace) => Class::Blank,
+            Some(g) if g.chars().next().is_some_and(|ch| ch.is_alphanumeric() || ch == '_') => Class::Word,
+            Some(_) => Class::Punct
+        }
+    }
+
+    fn next(&self, (row, x): (usize, usize)) -> Option<(usize, usize)> {
+        if x < self.lines[row].len() {
+            Some((row, x + 1))
+        } else if row + 1 < self.lines.len() {
+            Some((row + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    fn prev(&self, (row, x): (usize, usize)) -> Option<(usize, usize)> {
+        if x > 0 {
+            Some((row, x - 1))
+        } else if row > 0 {
+            Some((row - 1, self.lines[row - 1].len()))
+        } else {
+            None
+        }
+    }
+
+    // w: past the rest of this word and the blanks after it
+    fn word_start(&self, start: (usize, usize)) -> (usize, usize) {
+        if self.lines.is_empty() {
+            return start;
+        }
+        let mut at = start;
+        let class = self.class(at);
+        while self.class(at) == class {
+            match self.next(at) {
+                Some(next) => at = next,
+                None => return at
+            }
+            if class == Class::EmptyLine {
+                break;
+            }
+        }
+        while self.class(at) == Class::Blank {
+            match self.next(at) {
+                Some(next) => at = next,
+                None => break
+            }
+        }
+        at
+    }
+
+    // e: to the last character of this word or the next one
+    fn word_end(&self, start: (usize, usize)) -> (usize, usize) {
+        if self.lines.is_empty() {
+            return start;
+        }
+        let Some(mut at) = self.next(start) else {
+            return start;
+        };
+        while matches!(self.class(at), Class::Blank | Class::EmptyLine) {
+            match self.next(at) {
+                Some(next) => at = next,
+                None => return at
+            }
+        }
+        let class = self.class(at);
+        while let Some(next) = self.next(at).filter(|next| self.class(*next) == class) {
+            at = next;
+        }
+        at
+    }
+
+    // b: back to the first character of this word or the one before it
+    fn word_back(&self, start: (usize, usize)) -> (usize, usize) {
+        if self.lines.is_empty() {
+            return start;
+        }
+        let Some(mut at) = self.prev(start) else {
+            return start;
+        };
+        while self.class(at) == Class::Blank {
+            match self.prev(at) {
+                Some(prev) => at = prev,
+                None => return at
+            }
+        }
+        let class = self.class(at);
+        if class == Class::EmptyLine {
+            return at;
+        }
+        while let Some(prev) = self.prev(at).filter(|prev| self.class(*prev) == class) {
+            at = prev;
+        }
+        at
+    }
+}
+
+// What an operator works on
+pub enum Span {
+    // From one cursor position to another, the end excluded
+    Chars(CursorPos, CursorPos),
+    // Every row from the first to the last one
+    Lines(usize, usize)
+}
+
+// Keys which mean something in normal mode, as the character vi knows them by
+pub fn vi_char(key: &KeyEvent) -> Option<char> {
+    match (key.code, key.modifiers) {
+        (KeyCode::Char('r'), KeyModifiers::CONTROL) => Some('\x12'),
+        (KeyCode::Char(ch), KeyModifiers::NONE | KeyModifiers::SHIFT) => Some(ch),
+        (KeyCode::Left | KeyCode::Backspace, _) => Some('h'),
+        (KeyCode::Right, _) => Some('l'),
+        (KeyCode::Up, _) => Some('k'),
+        (KeyCode::Down | KeyCode::Enter, _) => Some('j'),
+        (KeyCode::Home, _) => Some('0'),
+        (KeyCode::End, _) => Some('$'),
+        (KeyCode::Delete, _) => Some('x'),
+        _ => None
+    }
+}
+
+// The two positions, the one nearer to the start of the file first
+pub fn ordered(a: CursorPos, b: CursorPos) -> (CursorPos, CursorPos) {
+    if (a.y, a.x) <= (b.y, b.x) { (a, b) } else { (b, a) }
+}
+
+pub fn on_blank(rows: &[Row], pos: CursorPos) -> bool {
+    rows.get(pos.y as usize)
+        .and_then(|row| row.characters.graphemes(true).nth(pos.x as usize))
+        .is_none_or(|g| g.chars().all(char::is_whitespace))
+}
+
+// Index of the first grapheme of the row which isn't blank, for I
+pub fn first_non_blank(row: &Row) -> usize {
+    row.characters
+        .graphemes(true)
+        .take_while(|g| g.chars().all(char::is_whitespace))
+        .count()
+}
@@ -0,0 +1,147 @@
+use kilo_ed_rust::*;
+
+// Columns taken by the line between two windows side by side
+const SEPARATOR_WIDTH: u16 = 1;
+
+// Smallest share of a split a window can be resized to, in percent
+const MIN_PERCENT: i16 = 10;
+
+// A view on a buffer. The active window's view lives in the Editor,
+// these fields keep it while another window has the focus.
+pub struct Window {
+    pub buffer: usize,
+    pub cursor: CursorPos,
+    pub rowoff: u16,
+    pub coloff: u16,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SplitDir {
+    // One window above the other
+    Horizontal,
+    // Windows side by side
+    Vertical
+}
+
+// How the screen is divided between the windows, as a tree of splits
+pub enum Layout {
+    Window(usize),
+    Split {
+        dir: SplitDir,
+        // Share of the first part, in percent
+        percent: u16,
+        first: Box<Layout>,
+        second: Box<Layout>
+    }
+}
+
+impl Layout {
+    // Function to find the area of every window and of the separators
+    // between windows side by side
+    pub fn arrange(&self, area: Rect, windows: &mut Vec<(usize, Rect)>, separators: &mut Vec<Rect>) {
+        match self {
+            Layout::Window(idx) => windows.push((*idx, area)),
+            Layout::Split { dir: SplitDir::Horizontal, percent, first, second } => {
+                let top = (area.height as u32 * *percent as u32 / 100) as u16;
+                first.arrange(Rect { height: top, ..area }, windows, separators);
+                second.arrange(Rect { y: area.y + top, height: area.height - top, ..area }, windows, separators);
+            },
+            Layout::Split { dir: SplitDir::Vertical, percent, first, second } => {
+                let usable = area.width.saturating_sub(SEPARATOR_WIDTH);
+                let left = (usable as u32 * *percent as u32 / 100) as u16;
+                first.arrange(Rect { width: left, ..area }, windows, separators);
+                separators.push(Rect { x: area.x + left, width: SEPARATOR_WIDTH.min(area.width), ..area });
+                second.arrange(Rect {
+                    x: area.x + left + SEPARATOR_WIDTH,
+                    width: usable - left,
+                    ..area
+                }, windows, separators);
+            }
+        }
+    }
+
+    // Function to split window idx in two, the new window goes below or to the right
+    pub fn split(&mut self, idx: usize, new: usize, dir: SplitDir) -> bool {
+        match self {
+            Layout::Window(current) if *current == idx => {
+                *self = Layout::Split {
+                    dir,
+                    percent: 50,
+                    first: Box::new(Layout::Window(idx)),
+                    second: Box::new(Layout::Window(new))
+                };
+                true
+            },
+            Layout::Window(_) => false,
+            Layout::Split { first, second, .. } => {
+                first.split(idx, new, dir) || second.split(idx, new, dir)
+            }
+        }
+    }
+
+    // Function to take window idx out, the other part of its split fills the space.
+    // The windows after idx move down by one, like in the Vec they are kept in.
+    pub fn remove(&mut self, idx: usize) {
+        self.take_out(idx);
+        self.renumber(idx);
+    }
+
+    fn take_out(&mut self, idx: usize) {
+        if let Layout::Split { first, second, .. } = self {
+            if matches!(**first, Layout::Window(current) if current == idx) {
+                *self = std::mem::replace(&mut **second, Layout::Window(0));
+            } else if matches!(**second, Layout::Window(current) if current == idx) {
+                *self = std::mem::replace(&mut **first, Layout::Window(0));
+            } else {
+                first.take_out(idx);
+                second.take_out(idx);
+            }
+        }
+    }
+
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            Layout::Window(current) => {
+                if *current > removed {
+                    *current -= 1;
+                }
+            },
+            Layout::Split { first, second, .. } => {
+                first.renumber(removed);
+                second.renumber(removed);
+            }
+        }
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        match self {
+            Layout::Window(current) => *current == idx,
+            Layout::Split { first, second, .. } => first.contains(idx) || second.contains(idx)
+        }
+    }
+
+    // Function to grow (or shrink, for a negative delta) window idx inside
+    // the nearest split going in the given direction
+    pub fn resize(&mut self, idx: usize, dir: SplitDir, delta: i16) -> bool {
+        match self {
+            Layout::Window(_) => false,
+            Layout::Split { dir: split_dir, percent, first, second } => {
+                let in_first = first.contains(idx);
+                if !in_first && !second.contains(idx) {
+                    return false;
+                }
+                let child = if in_first { first } else { second };
+                if child.resize(idx, dir, delta) {
+                    return true;
+                }
+                if *split_dir != dir {
+                    return false;
+                }
+
+                let delta = if in_first { delta } else { -delta };
+                *percent = (*percent as i16 + delta).clamp(MIN_PERCENT, 100 - MIN_PERCENT) as u16;
+                true
+            }
+        }
+    }
+}